@@ -14,15 +14,191 @@
 
 """Translation of the TCL example code from https://www.sqlite.org/undoredo.html."""
 
+import asyncio
+import bisect
+import collections
+import concurrent.futures
+import contextlib
+import enum
+import itertools
+import json
 import re
 import sqlite3
 import sys
+import threading
+import time
+import weakref
 
 
 if sys.version_info < (3, 6):
     sys.exit('Python version 3.6 or later is required')
 
 
+class UndoError(Exception):
+    """Error raised by the undo/redo system."""
+
+
+class ReadOnlyError(UndoError):
+    """Error raised when the connection cannot record changes."""
+
+
+class UnknownTableError(UndoError):
+    """Error raised by activate when a table does not exist."""
+
+
+class CorruptLogError(UndoError):
+    """Error raised by undo and redo when undolog lost the rows of a step."""
+
+
+class UntrackedWriteError(UndoError):
+    """Error raised by barrier in strict mode when a change was not recorded."""
+
+
+SavedUndoState = collections.namedtuple(
+    'SavedUndoState', ['undostack', 'redostack', 'firstlog', 'undolog'])
+SavedUndoState.__doc__ = """Undo/redo history returned by SQLiteUndoRedo.save_state.
+
+Only made of lists and integers, so state._asdict() can be written with json.dumps
+and read back with SavedUndoState(**json.loads(text)).
+"""
+
+BoundaryState = collections.namedtuple('BoundaryState', ['at_newest', 'at_oldest'])
+BoundaryState.__doc__ = """Whether the redo stack and the undo stack are empty."""
+
+
+_interval_ids = itertools.count()
+
+
+class Interval(list):
+    """A [begin, end] range of undolog seqs making one undo or redo step.
+
+    Compares and unpacks like the plain [begin, end] list it extends, and also
+    carries LABEL, as given to barrier_labeled or None, and AT, the time.time() of
+    the barrier that created it.  Both are None when unknown, e.g. for the steps
+    read back by restore_state or from a persistent history.
+    """
+
+    def __init__(self, begin, end, label=None, at=None):
+        super().__init__([begin, end])
+        self.label = label
+        self.at = at
+        # Identifies the step as it moves between the stacks, for checkpoints
+        self._id = next(_interval_ids)
+
+    @property
+    def begin(self):
+        return self[0]
+
+    @property
+    def end(self):
+        return self[1]
+
+    def copy(self):
+        return Interval(self[0], self[1], self.label, self.at)
+
+
+class FreezeState(enum.Enum):
+    """Whether new database changes are accepted into the undo stack.
+
+    INACTIVE before activate and after deactivate, when freeze and unfreeze do
+    nothing, THAWED while changes are accepted and FROZEN between freeze and
+    unfreeze.
+    """
+    INACTIVE = 'inactive'
+    THAWED = 'thawed'
+    FROZEN = 'frozen'
+
+
+ChangeRecord = collections.namedtuple('ChangeRecord', ['table', 'op', 'rowid', 'columns'])
+ChangeRecord.__doc__ = """One statement of an undo step, parsed from its SQL text.
+
+OP is 'INSERT', 'UPDATE' or 'DELETE' and tells what replaying the statement does.
+ROWID is None for WITHOUT ROWID tables and views, whose rows are located by the
+key values found in COLUMNS, a dict mapping column names to values.
+"""
+
+_LITERAL = r"'(?:[^']|'')*'|X'[0-9A-Fa-f]*'|[^,' )]+"
+_IDENTIFIER = r'"(?:[^"]|"")*"|\w+'
+
+# id(db) -> (WeakSet of the instances that turned recursive_triggers on, its value
+# before the first), so that a connection is never kept alive by the registry
+_recursive_trigger_users = {}
+
+
+def _sql_literal(value):
+    """Return VALUE written as an SQL literal."""
+    if value is None:
+        return "NULL"
+    if isinstance(value, (bytes, bytearray)):
+        return f"X'{bytes(value).hex()}'"
+    if isinstance(value, str):
+        return "'" + value.replace("'", "''") + "'"
+    return repr(value)
+
+
+def _quote_identifier(name):
+    """Return NAME written as a double-quoted SQL identifier."""
+    return '"' + name.replace('"', '""') + '"'
+
+
+def _unquote_identifier(name):
+    """Return NAME with the double quotes of _quote_identifier removed."""
+    if name.startswith('"'):
+        return name[1:-1].replace('""', '"')
+    return name
+
+
+def _fold_identifier(name):
+    """Return NAME lowercased the way SQLite compares identifiers, ASCII only."""
+    return re.sub("[A-Z]+", lambda m: m.group(0).lower(), name)
+
+
+def _schemas(db):
+    """Return the names of the databases attached to the handle DB."""
+    return [name for (x1, name, x2) in db.execute("pragma database_list")]
+
+
+def apply_changelog(db, jsonl):
+    """Apply a changelog written by SQLiteUndoRedo.export_changelog to DB.
+
+    The statements are run newest first, the same order an undo replays them,
+    so DB is taken back to its state before the recorded changes.  If one fails,
+    none is applied.  Returns the number of statements applied.
+    """
+    entries = [json.loads(line) for line in jsonl.splitlines() if line.strip()]
+    entries.sort(key=lambda entry: entry['seq'], reverse=True)
+    db.execute('SAVEPOINT apply_changelog')
+    try:
+        for entry in entries:
+            db.execute(entry['sql'])
+    except BaseException:
+        db.execute('ROLLBACK TO apply_changelog')
+        db.execute('RELEASE apply_changelog')
+        raise
+    db.execute('RELEASE apply_changelog')
+    return len(entries)
+
+
+def undo_squashed(db, table='undo_squashed'):
+    """Undo the history kept in TABLE by SQLiteUndoRedo.deactivate_squashing.
+
+    The statements are run newest first in a savepoint, then TABLE is dropped.
+    Returns the number of statements run.
+    """
+    sqllist = db.execute(f"SELECT sql FROM {table} ORDER BY seq DESC").fetchall()
+    db.execute('SAVEPOINT undo_squashed')
+    try:
+        for (sql,) in sqllist:
+            db.execute(sql)
+        db.execute(f"DROP TABLE {table}")
+    except BaseException:
+        db.execute('ROLLBACK TO undo_squashed')
+        db.execute('RELEASE undo_squashed')
+        raise
+    db.execute('RELEASE undo_squashed')
+    return len(sqllist)
+
+
 class SQLiteUndoRedo:
 
     def activate(self, *args):
@@ -30,17 +206,45 @@ class SQLiteUndoRedo:
 
         Arguments should be one or more database tables (in the database associated
         with the handle "db") whose changes are to be recorded for undo/redo
-        purposes.
+        purposes.  A list of table names built at run time can be unpacked with
+        activate(*tables).
+
+        The rows skipped by IGNORE and the statements undone by ABORT and ROLLBACK
+        are not recorded, the rows a statement changed before it failed with FAIL
+        are.  The rows deleted by REPLACE are only recorded with TRACK_REPLACE.  An
+        OR ROLLBACK failing inside a transaction also rolls back the undolog rows
+        of the barriers made during that transaction, as any ROLLBACK does, so the
+        history should then be reset.
+
+        An UndoError is raised if one of the tables has recording triggers with the
+        same prefix already, e.g. left by another instance that was never
+        deactivated.
         """
         _undo = self._undo
         if _undo['active']:
             return
+        leftover = self._find_triggers(self._db, args)
+        if leftover:
+            raise UndoError(f"triggers {', '.join(leftover)} already exist, deactivate"
+                            " the instance that created them first")
         self._create_triggers(self._db, *args)
+        if self._track_replace:
+            self._enable_recursive_triggers()
+        self._tables = list(args)
+        self._frozen_tables = set()
         _undo['undostack'] = []
         _undo['redostack'] = []
+        if self._persistent:
+            self._load_stacks()
+        self._generation += 1
         _undo['active'] = 1
-        _undo['freeze'] = -1
+        self._thaw()
         self._start_interval()
+        if self._persistent:
+            # Changes logged after the last barrier before the history was saved
+            # belong to no step yet: leave them pending for the next barrier
+            ends = [end for (_, end) in _undo['undostack'] + _undo['redostack']]
+            _undo['firstlog'] = max(ends, default=0) + 1
 
     def deactivate(self):
         """Halt the undo/redo system and delete the undo/redo stacks."""
@@ -48,48 +252,336 @@ class SQLiteUndoRedo:
         if not _undo['active']:
             return
         self._drop_triggers(self._db)
+        if self._persistent:
+            for table in (self._log_table, self._state_table):
+                self._db.execute(f"DROP TABLE IF EXISTS {self._log_schema}.{table}")
+        elif self._memory_log:
+            if 'undo_memory' in _schemas(self._db):
+                self._db.execute("DETACH DATABASE undo_memory")
+        self._restore_recursive_triggers()
+        self._tables = []
+        self._frozen_tables = set()
         _undo['undostack'] = []
         _undo['redostack'] = []
+        self._generation += 1
         _undo['active'] = 0
-        _undo['freeze'] = -1
+        self._freeze_state = FreezeState.INACTIVE
+        self._freeze_seq = None
+
+    def deactivate_squashing(self, table='undo_squashed'):
+        """Deactivate, keeping the whole undo history as one step in TABLE.
+
+        Pending changes are made an undo step first.  The statements of every undo
+        step are then copied, oldest first, into the permanent table TABLE(seq,
+        sql) of the database LOG_SCHEMA, replacing any table of that name, before
+        deactivate.  A later tool, even on another connection, can revert the
+        database to its state before the history with undo_squashed.  The redo
+        stack is discarded.  Returns the number of statements kept.
+        """
+        _undo = self._undo
+        if not _undo['active']:
+            return 0
+        self.barrier()
+        target = f"{self._log_schema}.{table}"
+        self._db.execute('SAVEPOINT undo_squash')
+        try:
+            self._db.execute(f"DROP TABLE IF EXISTS {target}")
+            self._db.execute(f"CREATE TABLE {target}(seq integer primary key, sql text)")
+            for (begin, end) in _undo['undostack']:
+                self._db.execute(f"INSERT INTO {target}(sql) SELECT sql"
+                                 f" FROM {self._log_table} WHERE seq>=? AND seq<=?"
+                                 " ORDER BY seq", (begin, end))
+            count = self._db.execute(f"SELECT count(*) FROM {target}").fetchone()[0]
+        except BaseException:
+            self._db.execute('ROLLBACK TO undo_squash')
+            self._db.execute('RELEASE undo_squash')
+            raise
+        self._db.execute('RELEASE undo_squash')
+        self.deactivate()
+        return count
+
+    def rebind(self, db):
+        """Move the undo/redo system to the database handle DB.
+
+        Meant for connection pools, where each request may get another connection
+        to the same database.  Only a PERSISTENT system can be moved, as the temp
+        database holding undolog otherwise belongs to the connection.  The pending
+        changes get a barrier, the recording triggers are dropped from the old
+        connection, which may then be handed back to the pool, and created on DB,
+        and the history goes on from the undolog and undostate tables.
+        """
+        if not self._persistent:
+            raise UndoError("only a persistent undo/redo system can be rebound")
+        if db.execute("pragma query_only").fetchone()[0]:
+            raise ReadOnlyError("the connection is query_only")
+        if not self._undo['active']:
+            self._db = db
+            return
+        self.barrier()
+        self._drop_triggers(self._db)
+        self._restore_recursive_triggers()
+        self._db = db
+        self._create_triggers(db, *self._tables)
+        for tbl in self._frozen_tables:
+            self._drop_table_triggers(db, tbl)
+        if self._track_replace:
+            self._enable_recursive_triggers()
+        self._start_interval()
+
+    def reset(self):
+        """Delete the undo/redo stacks and undolog, keeping the recording triggers.
+
+        Also ends any freeze in progress.
+        """
+        _undo = self._undo
+        if not _undo['active']:
+            return
+        self._db.execute(f"DELETE FROM {self._log_table}")
+        _undo['undostack'] = []
+        _undo['redostack'] = []
+        self._generation += 1
+        self._thaw()
+        self._start_interval()
+        self._save_stacks()
+
+    def clear_redo(self):
+        """Discard the redo stack and delete its undolog rows, keeping the undo stack.
+
+        Commits to the current timeline without making an edit, as the next barrier
+        recording changes would.
+        """
+        _undo = self._undo
+        if not _undo['active'] or not _undo['redostack']:
+            return
+        self._discard_redo()
+        self._save_stacks()
+
+    def compact(self):
+        """Renumber the undolog seqs to 1, 2, 3... and the intervals to match.
+
+        Dropping old intervals, e.g. with set_max_total_rows, leaves the seqs of the
+        remaining rows ever growing; compact brings them back down.
+        """
+        if not self._undo['active']:
+            return
+        self._renumber(1)
+        self._save_stacks()
+
+    def shrink_memory(self):
+        """Give back the memory left over by heavy pruning of the history.
+
+        Runs compact, copies the undo/redo stacks into lists of their current size,
+        and asks SQLite to free as much of the connection's memory as it can with
+        pragma shrink_memory.  Meant for long-lived processes short of memory.
+        """
+        _undo = self._undo
+        if not _undo['active']:
+            return
+        self.compact()
+        for stack in ('undostack', 'redostack'):
+            _undo[stack] = [interval.copy() for interval in _undo[stack]]
+        self._db.execute("pragma shrink_memory")
 
     def freeze(self):
         """Stop accepting database changes into the undo stack.
 
         From the point when this routine is called up until the next unfreeze,
-        new database changes are rejected from the undo stack.
+        new database changes are rejected from the undo stack.  Undo and redo
+        raise an UndoError meanwhile.
         """
-        _undo = self._undo
-        if 'freeze' not in _undo:
+        if self._freeze_state is FreezeState.INACTIVE:
+            return
+        if self._freeze_state is FreezeState.FROZEN:
+            raise Exception("recursive call to freeze")
+        self._freeze_seq = self._db.execute(
+            f"SELECT coalesce(max(seq),0) FROM {self._log_table}").fetchone()[0]
+        self._freeze_state = FreezeState.FROZEN
+
+    def freeze_at(self, seq):
+        """Freeze as if freeze had been called when undolog ended at SEQ.
+
+        The changes recorded after SEQ are dropped by the next unfreeze, as the ones
+        made from now on.  SEQ must lie between the start of the current interval
+        (minus one) and the last seq, because the rows of the undo/redo stacks
+        cannot be dropped; an UndoError is raised otherwise.
+        """
+        if self._freeze_state is FreezeState.INACTIVE:
             return
-        if _undo['freeze'] >= 0:
+        if self._freeze_state is FreezeState.FROZEN:
             raise Exception("recursive call to freeze")
-        _undo['freeze'] = self._db.execute(
-            "SELECT coalesce(max(seq),0) FROM undolog").fetchone()[0]
+        last = self.last_seq()
+        first = self._undo['firstlog'] - 1
+        if not first <= seq <= last:
+            raise UndoError(f"freeze seq {seq} is not between {first} and {last}")
+        self._freeze_seq = seq
+        self._freeze_state = FreezeState.FROZEN
 
     def unfreeze(self):
         """Begin accepting undo actions again."""
         _undo = self._undo
-        if 'freeze' not in _undo:
+        if self._freeze_state is FreezeState.INACTIVE:
             return
-        if _undo['freeze'] < 0:
+        if self._freeze_state is FreezeState.THAWED:
             raise Exception("called unfreeze while not frozen")
-        self._db.execute(f"DELETE FROM undolog WHERE seq>{_undo['freeze']}")
-        _undo['freeze'] = -1
+        self._db.execute(f"DELETE FROM {self._log_table} WHERE seq>{self._freeze_seq}")
+        # The deleted seqs get reused, so the current interval must not begin
+        # past them
+        _undo['firstlog'] = min(_undo['firstlog'], self._freeze_seq + 1)
+        self._thaw()
+        self._total_changes = None
+
+    def freeze_point(self):
+        """Return the last undolog seq kept by the current freeze, or None."""
+        if self._freeze_state is FreezeState.FROZEN:
+            return self._freeze_seq
+        return None
+
+    @contextlib.contextmanager
+    def frozen(self):
+        """Freeze for the duration of a with block.
+
+        unfreeze is called when the block exits, even through an exception or an
+        early return.
+        """
+        self.freeze()
+        try:
+            yield self
+        finally:
+            self.unfreeze()
+
+    def freeze_table(self, table):
+        """Stop recording the changes made to TABLE.
+
+        The triggers of TABLE are dropped until the next unfreeze_table, while the
+        other tables keep being recorded.
+        """
+        if table not in self._tables:
+            raise UndoError(f"table {table} is not tracked")
+        if table in self._frozen_tables:
+            raise UndoError(f"table {table} is already frozen")
+        self._drop_table_triggers(self._db, table)
+        self._frozen_tables.add(table)
+
+    def unfreeze_table(self, table):
+        """Start recording the changes made to TABLE again."""
+        if table not in self._frozen_tables:
+            raise UndoError(f"table {table} is not frozen")
+        self._create_triggers(self._db, table, keep_log=True)
+        self._frozen_tables.remove(table)
+
+    def track_table(self, table):
+        """Record TABLE, with triggers matching its current schema.
+
+        Call it after altering a recorded table, e.g. with ALTER TABLE ADD COLUMN,
+        so that the new columns are recorded too.  The history recorded before
+        stays valid, as its statements only name the columns that existed then;
+        after a change that makes them invalid, like dropping or renaming a
+        column, the history should be cleared with reset.
+        """
+        if not self._undo['active']:
+            raise UndoError("called track_table while not active")
+        self._create_triggers(self._db, table, keep_log=True)
+        if table not in self._tables:
+            self._tables.append(table)
+        self._frozen_tables.discard(table)
+
+    def is_tracking(self, table):
+        """Return whether the changes made to TABLE are currently recorded.
+
+        Like SQLite identifiers, TABLE is matched ignoring ASCII case.  A table
+        frozen by freeze_table is not being recorded.
+        """
+        name = _fold_identifier(table)
+        return any(_fold_identifier(tbl) == name for tbl in self._tables
+                   if tbl not in self._frozen_tables)
+
+    def reactivate_with(self, *args):
+        """Change the set of recorded tables to the tables listed.
+
+        Unlike deactivate followed by activate, the history is kept as far as it
+        stays consistent: the undo steps that modify a table no longer recorded are
+        discarded, together with every older undo step, and likewise for the redo
+        steps that modify such a table and every redo step after them.  Steps that
+        only modify tables still recorded are kept.
+        """
+        _undo = self._undo
+        if not _undo['active']:
+            self.activate(*args)
+            return
+        removed = set(self._tables) - set(args)
+        self._create_triggers(self._db, *args, keep_log=True)
+        for tbl in removed:
+            self._drop_table_triggers(self._db, tbl)
+        self._tables = list(args)
+        self._frozen_tables = set()
+        pending = self._pending_changes()
+        for stack in ('undostack', 'redostack'):
+            tainted = [i for (i, interval) in enumerate(_undo[stack])
+                       if removed.intersection(self._interval_tables(interval))]
+            if tainted:
+                for (begin, end) in _undo[stack][:tainted[-1] + 1]:
+                    self._db.execute(f"DELETE FROM {self._log_table}"
+                                     f" WHERE seq>={begin} AND seq<={end}")
+                del _undo[stack][:tainted[-1] + 1]
+                if stack == 'undostack':
+                    self._dropped_intervals += tainted[-1] + 1
+        self._skip_changes(pending)
+        self._save_stacks()
+
+    def set_active_tables(self, *args):
+        """Record the changes of exactly the tables listed.
+
+        Triggers are created for the listed tables not recorded yet and dropped
+        for the recorded tables not listed, leaving the undo/redo history alone.
+        Returns the lists of added and removed table names.
+        """
+        if not self._undo['active']:
+            self.activate(*args)
+            return (list(args), [])
+        added = [tbl for tbl in args if tbl not in self._tables]
+        removed = [tbl for tbl in self._tables if tbl not in args]
+        self._create_triggers(self._db, *added, keep_log=True)
+        for tbl in removed:
+            self._drop_table_triggers(self._db, tbl)
+            self._frozen_tables.discard(tbl)
+        self._tables = [tbl for tbl in self._tables if tbl in args] + added
+        return (added, removed)
 
     def event(self):
         """Something undoable has happened.
 
         This routine is called whenever an undoable action occurs.  Arrangements
         are made to invoke ::undo::barrier no later than the next idle moment.
+
+        Python has no event loop of its own, so the arrangement is only a note in
+        _undo['pending']: the application must call on_idle from its idle handler,
+        e.g. with tkinter's after_idle, for the barrier to happen.  Until then, the
+        changes stay pending and a barrier called meanwhile takes them as usual.
         """
         _undo = self._undo
-        if _undo['pending'] == "":
-            raise NotImplementedError
+        if not _undo['pending']:
+            _undo['pending'] = [1]
             # set _undo(pending) after idle ::undo::barrier
 
+    def on_idle(self):
+        """Create the undo barrier arranged by event, if any.
+
+        Does nothing when event was not called since the last barrier, so it can be
+        called at every idle moment.
+        """
+        if self._undo['pending']:
+            self.barrier()
+
     def barrier(self):
-        """Create an undo barrier right now."""
+        """Create an undo barrier right now.
+
+        Nothing is pushed on the undo stack when no change was recorded since the
+        previous barrier, e.g. after an INSERT OR IGNORE that ignored its row, or
+        when all of the changes were made while frozen.  Such a barrier costs a
+        single query and leaves the stacks, the start of the pending changes and
+        the coalescing window as they were, so barrier may be called as often as
+        convenient, e.g. on every idle event.  It does nothing while not active.
+        """
         _undo = self._undo
         try:
             pass
@@ -100,25 +592,438 @@ class SQLiteUndoRedo:
         if not _undo['active']:
             # self.refresh()
             return
-        end = self._db.execute("SELECT coalesce(max(seq),0) FROM undolog").fetchone()[0]
-        if _undo['freeze'] >= 0 and end > _undo['freeze']:
-            end = _undo['freeze']
+        if self._stepping:
+            # _step records its own interval
+            return
+        end = self.last_seq()
         begin = _undo['firstlog']
-        self._start_interval()
-        if begin == _undo['firstlog']:
+        untracked = self._untracked_changes(begin, end)
+        if self._freeze_state is FreezeState.FROZEN and end > self._freeze_seq:
+            # Changes made while frozen are deleted by unfreeze
+            end = self._freeze_seq
+        if end < begin:
+            if untracked:
+                self._mark_changes()
+                raise UntrackedWriteError(f"{untracked} changes were not recorded")
             # self.refresh()
             return
-        _undo['undostack'].append([begin, end])
-        _undo['redostack'] = []
+        self._start_interval()
+        now = time.monotonic()
+        if (self._coalesce_window is not None and self._last_barrier is not None
+                and now - self._last_barrier < self._coalesce_window
+                and _undo['undostack']):
+            _undo['undostack'][-1][1] = end
+            if self._barrier_label is not None:
+                _undo['undostack'][-1].label = self._barrier_label
+        else:
+            _undo['undostack'].append(
+                Interval(begin, end, self._barrier_label, time.time()))
+        self._last_barrier = now
+        if not self._keeping_redo and _undo['redostack']:
+            if self._redo_discarded_callback is not None:
+                self._redo_discarded_callback(len(_undo['redostack']))
+            self._discard_redo()
+        self._evict_intervals()
+        self._save_stacks()
         # self.refresh()
+        if untracked:
+            raise UntrackedWriteError(f"{untracked} changes were not recorded")
+
+    def barrier_labeled(self, label):
+        """Create an undo barrier, naming the new undo step LABEL.
+
+        The label is kept by the step as it moves between the undo and redo
+        stacks, and returned by undo_steps and redo_steps.
+        """
+        self._barrier_label = label
+        try:
+            self.barrier()
+        finally:
+            self._barrier_label = None
+
+    def barrier_keep_redo(self):
+        """Create an undo barrier without discarding the redo stack.
+
+        Unlike the usual undo model, the redo steps stay available after the new
+        step, for side-channel changes like an autosave that should not cost the
+        user their redo history.  A later redo replays its step on top of the new
+        one, so the two should touch different rows; the redone step then goes on
+        the undo stack above the new one.
+        """
+        self._keeping_redo = True
+        try:
+            self.barrier()
+        finally:
+            self._keeping_redo = False
+
+    @contextlib.contextmanager
+    def group(self):
+        """Make the changes done in a with block a single undo step.
+
+        barrier is called when the block exits, even through an exception.
+
+            with sqlur.group():
+                db.execute(...)
+                db.execute(...)
+        """
+        try:
+            yield self
+        finally:
+            self.barrier()
+
+    def transaction(self, edit):
+        """Call EDIT with the database handle and make its changes one undo step.
+
+        EDIT runs in a savepoint followed by a barrier, and its return value is
+        returned.  If EDIT raises, its changes are rolled back, no undo step is
+        pushed and the exception propagates.
+        """
+        self._db.execute('SAVEPOINT undo_transaction')
+        try:
+            result = edit(self._db)
+        except BaseException:
+            self._db.execute('ROLLBACK TO undo_transaction')
+            self._db.execute('RELEASE undo_transaction')
+            # The rolled back changes still count in total_changes
+            self._mark_changes()
+            raise
+        self._db.execute('RELEASE undo_transaction')
+        self.barrier()
+        return result
 
     def undo(self):
-        """Do a single step of undo."""
-        self._step('undostack', 'redostack')
+        """Do a single step of undo.
+
+        Returns the number of statements replayed.
+        """
+        return self._step('undostack', 'redostack')
+
+    def undo_n(self, count):
+        """Undo the top COUNT steps of the undo stack as a whole.
+
+        The steps are undone in a savepoint, so either all of them are undone or,
+        if one fails, none.  Returns the number of statements replayed.
+        """
+        if count > len(self._undo['undostack']):
+            raise UndoError(f"cannot undo {count} steps, the undo stack has "
+                            f"{len(self._undo['undostack'])}")
+        return self._steps('undostack', 'redostack', count)
+
+    def undo_cost(self, count):
+        """Return the number of statements undo_n(COUNT) would replay.
+
+        Only the sizes of the top COUNT intervals of the undo stack are summed, so
+        this is cheap enough for sizing a progress bar before a large revert.
+        """
+        undostack = self._undo['undostack']
+        if count > len(undostack):
+            raise UndoError(f"cannot undo {count} steps, the undo stack has "
+                            f"{len(undostack)}")
+        return sum(end - begin + 1
+                   for (begin, end) in undostack[len(undostack) - count:])
+
+    def undo_to(self, label):
+        """Undo the steps recorded after the newest undo step labeled LABEL.
+
+        The labeled step stays on top of the undo stack.  The steps are undone as a
+        whole, like undo_n.  An UndoError is raised if no undo step has that label.
+        Returns the number of statements replayed.
+        """
+        undostack = self._undo['undostack']
+        for i in reversed(range(len(undostack))):
+            if undostack[i].label == label:
+                return self._steps('undostack', 'redostack', len(undostack) - 1 - i)
+        raise UndoError(f"no undo step is labeled {label!r}")
+
+    def redo_to(self, label):
+        """Redo the steps up to and including the next redo step labeled LABEL.
+
+        The labeled step ends on top of the undo stack, as after undo_to.  The
+        steps are redone as a whole.  An UndoError is raised if no redo step has
+        that label.  Returns the number of statements replayed.
+        """
+        redostack = self._undo['redostack']
+        for i in reversed(range(len(redostack))):
+            if redostack[i].label == label:
+                return self._steps('redostack', 'undostack', len(redostack) - i)
+        raise UndoError(f"no redo step is labeled {label!r}")
+
+    def undo_one_statement(self):
+        """Undo only the last recorded statement of the top undo step.
+
+        The rest of the step stays on the undo stack, and the undone statement
+        becomes a step of its own on the redo stack.  Returns the number of
+        statements replayed.
+        """
+        undostack = self._undo['undostack']
+        if undostack and undostack[-1][0] < undostack[-1][1]:
+            top = undostack.pop()
+            (begin, end) = top
+            undostack.extend([Interval(begin, end - 1, top.label, top.at),
+                              Interval(end, end, top.label, top.at)])
+        return self.undo()
+
+    def merge_last(self, n):
+        """Merge the top N steps of the undo stack into a single step.
+
+        When the steps are not adjacent in undolog, e.g. after an undo and a redo,
+        their rows are moved after the last one first, followed by the pending
+        changes, so that the merged step covers no other row.
+        """
+        _undo = self._undo
+        undostack = _undo['undostack']
+        if n > len(undostack):
+            raise UndoError(f"cannot merge {n} steps, the undo stack has "
+                            f"{len(undostack)}")
+        if n < 2:
+            return
+        (begin, end) = (undostack[-n][0], undostack[-1][1])
+        if sum(last - first + 1 for (first, last) in undostack[-n:]) != end - begin + 1:
+            (begin, end) = self._move_to_end(undostack[-n:])
+        merged = Interval(begin, end, undostack[-1].label, undostack[-1].at)
+        del undostack[-n:]
+        undostack.append(merged)
+        self._save_stacks()
+
+    def checkpoint(self):
+        """Return an opaque checkpoint of the current undo position.
+
+        The changes made after a checkpoint can be discarded at once by
+        rollback_to_checkpoint.
+        """
+        undostack = self._undo['undostack']
+        # Do not let the coalescing window fold later changes into the top step
+        self._last_barrier = None
+        return (self._generation, self._dropped_intervals + len(undostack),
+                undostack[-1]._id if undostack else None)
+
+    def rollback_to_checkpoint(self, checkpoint):
+        """Undo every change made since CHECKPOINT, as returned by checkpoint.
+
+        Pending changes are made an undo step first.  The steps are undone in a
+        savepoint, so either all of them are undone or, if one fails, none.  An
+        UndoError is raised if the checkpoint is no longer reachable: its steps
+        were dropped by set_max_total_rows or reactivate_with, undone and replaced
+        by new steps, merged with later steps, or the history was reset, restored
+        or deactivated since.
+        """
+        _undo = self._undo
+        self.barrier()
+        (generation, position, top) = checkpoint
+        depth = position - self._dropped_intervals
+        if (generation != self._generation or not 0 <= depth <= len(_undo['undostack'])
+                or (depth and _undo['undostack'][depth - 1]._id != top)):
+            raise UndoError("the checkpoint is no longer reachable")
+        self._steps('undostack', 'redostack', len(_undo['undostack']) - depth)
 
     def redo(self):
-        """Redo a single step."""
-        self._step('redostack', 'undostack')
+        """Redo a single step.
+
+        Returns the number of statements replayed.
+        """
+        return self._step('redostack', 'undostack')
+
+    def boundary_state(self):
+        """Return a BoundaryState telling whether redo and undo are exhausted."""
+        _undo = self._undo
+        return BoundaryState(at_newest=len(_undo['redostack']) == 0,
+                             at_oldest=len(_undo['undostack']) == 0)
+
+    def conn(self):
+        """Return the database handle the undo/redo system was created with.
+
+        Changes written through it are recorded by the triggers like any other, but
+        only become an undo step at the next barrier.
+        """
+        return self._db
+
+    def depths(self):
+        """Return the number of undo steps and of redo steps, (0, 0) when not active."""
+        _undo = self._undo
+        if not _undo['active']:
+            return (0, 0)
+        return (len(_undo['undostack']), len(_undo['redostack']))
+
+    def undo_intervals(self):
+        """Iterate over the (begin, end) intervals of the undo stack, oldest first."""
+        return (tuple(interval) for interval in self._undo['undostack'])
+
+    def redo_intervals(self):
+        """Iterate over the (begin, end) intervals of the redo stack, oldest first."""
+        return (tuple(interval) for interval in self._undo['redostack'])
+
+    def undo_steps(self):
+        """Iterate over copies of the Interval of each undo step, oldest first."""
+        return (interval.copy() for interval in self._undo['undostack'])
+
+    def redo_steps(self):
+        """Iterate over copies of the Interval of each redo step, oldest first."""
+        return (interval.copy() for interval in self._undo['redostack'])
+
+    def peek_undo(self):
+        """Return the (begin, end) interval the next undo would replay, or None."""
+        return self._peek('undostack')
+
+    def peek_redo(self):
+        """Return the (begin, end) interval the next redo would replay, or None."""
+        return self._peek('redostack')
+
+    def undo_label(self):
+        """Return the label of the step the next undo would replay, or None.
+
+        Meant for menu entries like "Undo Typing"; None when the step has no label
+        or there is nothing to undo.
+        """
+        return self._top_label('undostack')
+
+    def redo_label(self):
+        """Return the label of the step the next redo would replay, or None."""
+        return self._top_label('redostack')
+
+    def would_undo_change_rows(self):
+        """Return how many rows the next undo step would change.
+
+        The step is replayed in a savepoint which is then rolled back, leaving the
+        database and undolog untouched.
+        """
+        _undo = self._undo
+        if not _undo['undostack']:
+            return 0
+        (begin, end) = _undo['undostack'][-1]
+        q1 = f"SELECT sql FROM {self._log_table} WHERE seq>={begin} AND seq<={end}" \
+             " ORDER BY seq DESC"
+        changes = 0
+        pending = self._pending_changes()
+        self._db.execute('SAVEPOINT undo_dry_run')
+        try:
+            for (sql,) in self._db.execute(q1).fetchall():
+                changes += self._db.execute(sql).rowcount
+        finally:
+            self._db.execute('ROLLBACK TO undo_dry_run')
+            self._db.execute('RELEASE undo_dry_run')
+            # The rolled back changes still count in total_changes
+            self._skip_changes(pending)
+        return changes
+
+    def last_step_changes(self):
+        """Return how many rows the last undo or redo changed.
+
+        For undo_n and the other methods replaying several steps, the rows changed
+        by all of them.  0 after a step whose statements matched no row, e.g. when
+        the rows were changed behind the back of the undo/redo system, and before
+        the first step.
+        """
+        return self._last_step_changes
+
+    def first_log(self):
+        """Return the first undolog seq of the changes not in an undo step yet."""
+        return self._undo['firstlog']
+
+    def last_seq(self):
+        """Return the seq of the newest undolog row, or 0 when empty or not active.
+
+        It grows whenever a change is recorded, so polling it tells whether the
+        database was changed since, without a barrier.
+        """
+        if not self._undo['active']:
+            return 0
+        return self._db.execute(
+            f"SELECT coalesce(max(seq),0) FROM {self._log_table}").fetchone()[0]
+
+    def has_pending_changes(self):
+        """Return whether changes were recorded that the next barrier would capture.
+
+        The changes made while frozen do not count, unfreeze drops them.
+        """
+        if not self._undo['active']:
+            return False
+        end = self.last_seq()
+        if self._freeze_state is FreezeState.FROZEN:
+            end = min(end, self._freeze_seq)
+        return end >= self._undo['firstlog']
+
+    def verify(self):
+        """Return whether the undo/redo stacks are consistent with undolog.
+
+        The invariants checked are that every interval of both stacks has
+        begin <= end and a row in undolog for each seq from begin to end, that no
+        two intervals share a seq, and that every interval ends before the first
+        seq of the pending changes.  Meant for debugging and for assertions in the
+        application's tests; always true when not active.
+        """
+        _undo = self._undo
+        if not _undo['active']:
+            return True
+        intervals = sorted(_undo['undostack'] + _undo['redostack'])
+        q = f"SELECT count(*) FROM {self._log_table} WHERE seq>=? AND seq<=?"
+        previous_end = 0
+        for (begin, end) in intervals:
+            if not previous_end < begin <= end < _undo['firstlog']:
+                return False
+            if self._db.execute(q, (begin, end)).fetchone()[0] != end - begin + 1:
+                return False
+            previous_end = end
+        return True
+
+    def log_row_count(self):
+        """Return the number of rows in undolog, or 0 when not active."""
+        if not self._undo['active']:
+            return 0
+        return self._db.execute(f"SELECT count(*) FROM {self._log_table}").fetchone()[0]
+
+    def log_size_bytes(self):
+        """Return the total length of the SQL text in undolog, or 0 when not active."""
+        if not self._undo['active']:
+            return 0
+        return self._db.execute(
+            f"SELECT coalesce(sum(length(sql)),0) FROM {self._log_table}").fetchone()[0]
+
+    def undo_affected_tables(self):
+        """Return the names of the tables the next undo step will modify."""
+        _undo = self._undo
+        if not _undo['undostack']:
+            return []
+        return self._interval_tables(_undo['undostack'][-1])
+
+    def preview_undo(self):
+        """Return the SQL statements the next undo would replay, in replay order."""
+        return list(self.preview_undo_iter())
+
+    def preview_undo_iter(self):
+        """Iterate over the SQL statements the next undo would replay.
+
+        The undolog rows are read lazily, one at a time, so that a huge step can be
+        looked at without holding all of its statements in memory.  The iterator
+        should be exhausted or dropped before the database is changed again.
+        """
+        _undo = self._undo
+        if not _undo['undostack']:
+            return iter(())
+        (begin, end) = _undo['undostack'][-1]
+        q1 = f"SELECT sql FROM {self._log_table} WHERE seq>={begin} AND seq<={end}" \
+             " ORDER BY seq DESC"
+        return (sql for (sql,) in self._db.execute(q1))
+
+    def dump_history(self):
+        """Return every undo step as a (begin, end, statements) tuple, oldest first.
+
+        The statements of each step are the SQL text undo would replay, in replay
+        order, as with preview_undo.  The redo stack is not included.
+        """
+        q1 = f"SELECT sql FROM {self._log_table} WHERE seq>=? AND seq<=?" \
+             " ORDER BY seq DESC"
+        return [(begin, end, [sql for (sql,) in self._db.execute(q1, (begin, end))])
+                for (begin, end) in self._undo['undostack']]
+
+    def structured_undo(self):
+        """Return the next undo step as a list of ChangeRecord, in replay order."""
+        _undo = self._undo
+        if not _undo['undostack']:
+            return []
+        (begin, end) = _undo['undostack'][-1]
+        q1 = f"SELECT sql FROM {self._log_table} WHERE seq>={begin} AND seq<={end}" \
+             " ORDER BY seq DESC"
+        return [self._change_record(sql) for (sql,) in self._db.execute(q1).fetchall()]
 
     def refresh(self):
         """Update the status of controls after a database change.
@@ -154,8 +1059,248 @@ class SQLiteUndoRedo:
         # proc ::undo::reload_all {} $body
         # reload_all
 
-    def __init__(self, db):
+    def export_changelog(self):
+        """Return the content of undolog as JSON lines.
+
+        Each line holds the "seq" and "sql" of one undolog row, oldest first.  The
+        result can be replayed with apply_changelog.
+        """
+        rows = self._db.execute(
+            f"SELECT seq, sql FROM {self._log_table} ORDER BY seq").fetchall()
+        return "".join(json.dumps({'seq': seq, 'sql': sql}) + "\n" for (seq, sql) in rows)
+
+    def save_state(self):
+        """Return the undo/redo stacks and the undolog rows as a SavedUndoState."""
+        _undo = self._undo
+        rows = self._db.execute(
+            f"SELECT seq, sql FROM {self._log_table} ORDER BY seq").fetchall()
+        return SavedUndoState(
+            undostack=[list(interval) for interval in _undo['undostack']],
+            redostack=[list(interval) for interval in _undo['redostack']],
+            firstlog=_undo['firstlog'],
+            undolog=[list(row) for row in rows],
+        )
+
+    def restore_state(self, state):
+        """Replace the undo/redo history with STATE, as returned by save_state.
+
+        The system must be active, on a database whose content matches the one the
+        state was saved from.  If STATE cannot be written to undolog, the history
+        is left as it was.
+        """
+        _undo = self._undo
+        if not _undo['active']:
+            raise UndoError("called restore_state while not active")
+        self._db.execute('SAVEPOINT undo_state')
+        try:
+            self._db.execute(f"DELETE FROM {self._log_table}")
+            self._db.executemany(f"INSERT INTO {self._log_table} VALUES(?, ?)",
+                                 state.undolog)
+        except BaseException:
+            self._db.execute('ROLLBACK TO undo_state')
+            self._db.execute('RELEASE undo_state')
+            raise
+        self._db.execute('RELEASE undo_state')
+        _undo['undostack'] = [Interval(*interval) for interval in state.undostack]
+        _undo['redostack'] = [Interval(*interval) for interval in state.redostack]
+        _undo['firstlog'] = state.firstlog
+        self._generation += 1
+        self._mark_changes()
+        self._save_stacks()
+
+    def export_json(self):
+        """Return the undo/redo history and freeze point as a JSON text.
+
+        Meant for attaching to bug reports; the text holds the fields of
+        save_state plus "freeze", the freeze_point, and can be read back by
+        import_json.
+        """
+        state = dict(self.save_state()._asdict())
+        state['freeze'] = self.freeze_point()
+        return json.dumps(state)
+
+    def import_json(self, text):
+        """Replace the undo/redo history with TEXT, as returned by export_json.
+
+        Same requirements as restore_state.
+        """
+        state = json.loads(text)
+        freeze = state.pop('freeze', None)
+        self.restore_state(SavedUndoState(**state))
+        if freeze is None:
+            self._thaw()
+        else:
+            self._freeze_state = FreezeState.FROZEN
+            self._freeze_seq = freeze
+
+    def set_max_total_rows(self, n):
+        """Limit the number of rows in undolog to N.
+
+        Whenever barrier finds more than N rows in undolog, the oldest intervals of
+        the undo stack are dropped, and their rows deleted, until the count is back
+        under the limit or the undo stack is empty.  None removes the limit.
+        """
+        self._max_total_rows = n
+
+    def set_progress_callback(self, callback, every=1000):
+        """Report the progress of undo and redo steps to CALLBACK.
+
+        CALLBACK is called with the number of statements replayed so far and the
+        total number of statements of the step, after every EVERY statements and
+        after the last one.  None removes the callback.
+        """
+        self._progress_callback = callback
+        self._progress_every = every
+
+    def on_replay(self, callback):
+        """Call CALLBACK with the SQL text of each statement replayed by undo or redo.
+
+        CALLBACK is called inside the step's savepoint, just before the statement
+        is run, and its return value is ignored.  None removes the callback.
+        """
+        self._replay_callback = callback
+
+    def on_redo_discarded(self, callback):
+        """Call CALLBACK with the number of redo steps a barrier is about to discard.
+
+        Only called when the redo stack was not empty.  None removes the callback.
+        """
+        self._redo_discarded_callback = callback
+
+    def set_coalesce_window(self, seconds):
+        """Merge undo steps recorded less than SECONDS apart.
+
+        When barrier records an interval less than SECONDS after the previous
+        barrier recorded one, the new changes are added to the top interval of the
+        undo stack instead of being pushed as a new step.  Undo and redo end the
+        merging.  None, the default, disables it.
+        """
+        self._coalesce_window = seconds
+
+    def set_replay_user_triggers(self, enabled):
+        """Choose whether the application's own triggers fire during undo and redo.
+
+        By default they do.  A trigger on a recorded table that writes to another
+        recorded table then repeats during redo a change the step already holds,
+        which usually fails, e.g. on a UNIQUE rowid, and the step is rolled back.
+        When disabled, the other triggers of the recorded tables are dropped for
+        the duration of each step and created again from their SQL text, inside
+        the step's savepoint.
+        """
+        self._replay_user_triggers = enabled
+
+    def set_strict(self, strict):
+        """Check that every change made to the database is recorded.
+
+        In strict mode, barrier compares the number of rows changed on the
+        connection since the previous barrier with the number of changes recorded
+        in undolog, and raises an UntrackedWriteError when some changes were not
+        recorded, e.g. a write to a table that is not tracked.  The interval is
+        still pushed on the undo stack.  Checking starts with the next interval,
+        and intervals during which unfreeze was called are not checked.  With
+        TRACK_REPLACE, the rows deleted by INSERT OR REPLACE are recorded but not
+        counted by SQLite, so they may hide as many unrecorded changes.
+        """
+        self._strict = strict
+
+    def exclude_column(self, table, column, placeholder=None):
+        """Leave COLUMN of TABLE out of the recorded undo SQL.
+
+        Undo no longer restores the value of an excluded column.  When a deleted
+        row is re-inserted, the column gets PLACEHOLDER if one is given, or its
+        default value otherwise.  A NOT NULL column without a default therefore
+        needs a placeholder, else activate raises an UndoError.  Must be called
+        before activate.
+        """
+        self._excluded_columns.setdefault(table, {})[column] = placeholder
+
+    def __init__(self, db, persistent=False, log_schema='main', memory_log=False,
+                 trigger_prefix='', track_autoincrement=False, skip_noop_updates=False,
+                 log_table='undolog', track_replace=False):
+        """Create the undo/redo system for the database handle DB.
+
+        If PERSISTENT is true, undolog is created as a permanent table of the
+        database LOG_SCHEMA, and the undo/redo stacks are kept in a companion table
+        named "undostate".  The history then survives closing the connection and is
+        reloaded by the next activate, at the cost of writing every recorded change
+        to the database file.  Changes recorded after the last barrier are not lost:
+        the first barrier after the reload makes them an undo step.  deactivate drops
+        both tables.
+
+        LOG_SCHEMA may name an attached database, e.g. one put in WAL mode so that
+        other connections can read the history while changes are being recorded
+        without blocking the writer.  Note that a transaction spanning a WAL
+        database and another file is only atomic for each file separately.
+
+        If MEMORY_LOG is true (and PERSISTENT false), undolog is created in an
+        in-memory database attached as "undo_memory" instead of the temp database,
+        so that recording changes never writes to disk, whatever temp_store is.
+        deactivate detaches it.
+
+        The recording triggers of a table TBL are named TRIGGER_PREFIX + "_TBL_it",
+        "_TBL_ut" and "_TBL_dt", and deactivate only drops triggers named so.
+
+        If TRACK_AUTOINCREMENT is true, the sqlite_sequence value of the AUTOINCREMENT
+        tables is recorded for each insert (by a fourth trigger "_TBL_st"), so that
+        undoing the insert also gives the id back.  Plain rowid tables have no
+        sequence and reuse the largest rowid plus one anyway.  An INSERT OR IGNORE
+        whose row is ignored records nothing, although it may still bump the
+        sequence.
+
+        LOG_TABLE names the undolog table, for instance so that several instances
+        with distinct LOG_TABLE and TRIGGER_PREFIX can record disjoint sets of
+        tables of the same connection, each with its own history.  The undostate
+        table of a persistent history is then named LOG_TABLE + "_state".
+
+        If SKIP_NOOP_UPDATES is true, an UPDATE leaving every recorded column of a
+        row unchanged, like "UPDATE tbl SET a=a", is not recorded, so that it does
+        not make an undo step of its own.
+
+        If TRACK_REPLACE is true, recursive_triggers is turned on from activate to
+        deactivate, because the rows deleted by a REPLACE conflict resolution only
+        fire the delete triggers then.  It applies to the whole connection, so the
+        application's own triggers become recursive too: a trigger updating the row
+        that fired it, e.g. to set a modification time, then fires itself again.
+
+        A ReadOnlyError is raised if DB has query_only set.
+        """
+        if db.execute("pragma query_only").fetchone()[0]:
+            raise ReadOnlyError("the connection is query_only")
         self._db = db
+        self._persistent = persistent
+        self._log_schema = log_schema
+        self._memory_log = memory_log
+        self._trigger_prefix = trigger_prefix
+        self._track_autoincrement = track_autoincrement
+        self._skip_noop_updates = skip_noop_updates
+        self._track_replace = track_replace
+        self._recursive_triggers = False
+        self._log_table = log_table
+        self._state_table = "undostate"
+        if log_table != "undolog":
+            self._state_table = f"{log_table}_state"
+        self._noop_function = f"{log_table}_noop_update"
+        self._excluded_columns = {}
+        self._tables = []
+        self._frozen_tables = set()
+        self._freeze_state = FreezeState.INACTIVE
+        self._freeze_seq = None
+        self._generation = 0
+        self._dropped_intervals = 0
+        self._max_total_rows = None
+        self._stepping = False
+        self._keeping_redo = False
+        self._barrier_label = None
+        self._progress_callback = None
+        self._replay_callback = None
+        self._replay_user_triggers = True
+        self._last_step_changes = 0
+        self._redo_discarded_callback = None
+        self._strict = False
+        self._coalesce_window = None
+        self._last_barrier = None
+        self._total_changes = None
+        self._progress_every = 1000
 
         # state information
         #
@@ -167,6 +1312,52 @@ class SQLiteUndoRedo:
         self._undo['firstlog'] = 1
         self._undo['startstate'] = []
 
+    def __del__(self):
+        """Drop the recording triggers left on the connection, ignoring errors.
+
+        A persistent history keeps its tables, to be reloaded by the next activate.
+        In both cases recursive_triggers is restored as by deactivate.
+        """
+        if not getattr(self, '_undo', {}).get('active'):
+            return
+        try:
+            if self._persistent:
+                self._drop_triggers(self._db)
+            else:
+                self.deactivate()
+        except sqlite3.Error:
+            pass
+        self._restore_recursive_triggers()
+
+    @classmethod
+    def activated(cls, db, tables, **kwargs):
+        """Return a SQLiteUndoRedo on DB already activated for TABLES.
+
+        KWARGS are passed to the constructor.  Errors of activate propagate.
+        """
+        sqlur = cls(db, **kwargs)
+        sqlur.activate(*tables)
+        return sqlur
+
+    @classmethod
+    def builder(cls, db):
+        """Return a SQLiteUndoRedoBuilder configuring a SQLiteUndoRedo on DB."""
+        return SQLiteUndoRedoBuilder(cls, db)
+
+    @classmethod
+    def recorded(cls, db, tables, edits):
+        """Return a SQLiteUndoRedo on DB with EDITS already recorded.
+
+        The undo/redo system is activated for TABLES, then each callable of EDITS is
+        called with DB and followed by a barrier, so that every edit becomes one
+        undo step.  Meant for building test fixtures.
+        """
+        sqlur = cls.activated(db, tables)
+        for edit in edits:
+            edit(db)
+            sqlur.barrier()
+        return sqlur
+
     def status_refresh(self):
         """Enable and/or disable menu options a buttons."""
         _undo = self._undo
@@ -187,91 +1378,795 @@ class SQLiteUndoRedo:
             # .mb.edit entryconfig Redo -state normal
             # .bb.redo config -state normal
 
-    @staticmethod
-    def _create_triggers(db, *args):
+    def _create_triggers(self, db, *args, keep_log=False):
         """Create change recording triggers for all tables listed.
 
         Create a temporary table in the database named "undolog".  Create
         triggers that fire on any insert, delete, or update of TABLE1, TABLE2, ....
         When those triggers fire, insert records in undolog that contain
         SQL text for statements that will undo the insert, delete, or update.
+
+        A view is recorded with INSTEAD OF triggers, and its rows are matched on all
+        of their columns.  The view must be made updatable by INSTEAD OF triggers of
+        its own, and its underlying tables should not be recorded as well.
+
+        An UnknownTableError is raised if a table does not exist.  A permanent table
+        named "undolog" is never dropped; an UndoError is raised instead.  Columns
+        passed to exclude_column are left out of the undo SQL.
+
+        If KEEP_LOG is true, undolog is left as it is, with the history it holds,
+        and the triggers the listed tables may already have are replaced.  Nothing
+        is dropped if one of the tables is unknown or a trigger cannot be created.
         """
+        q = "SELECT 1 FROM sqlite_master WHERE type='table' AND name=?"
+        if (not self._persistent and not keep_log
+                and db.execute(q, (self._log_table,)).fetchone()):
+            raise UndoError(f"a permanent table named {self._log_table} already exists")
+        self._check_tables(db, args)
+        if self._skip_noop_updates:
+            db.create_function(self._noop_function, 0, self._count_noop_update)
+        sqllist = [sql for tbl in args for sql in self._table_triggers(db, tbl)]
+        if not keep_log:
+            self._create_log(db)
+        # Unlike executescript, a savepoint does not commit the transaction the
+        # application may have open
+        db.execute('SAVEPOINT undo_triggers')
         try:
-            db.execute("DROP TABLE undolog")
-        except sqlite3.OperationalError:
-            pass
-        db.execute("CREATE TEMP TABLE undolog(seq integer primary key, sql text)")
-        for tbl in args:
-            collist = db.execute(f"pragma table_info({tbl})").fetchall()
-            sql = f"CREATE TEMP TRIGGER _{tbl}_it AFTER INSERT ON {tbl} BEGIN\n"
-            sql += "  INSERT INTO undolog VALUES(NULL,"
-            sql += f"'DELETE FROM {tbl} WHERE rowid='||new.rowid);\nEND;\n"
-
-            sql += f"CREATE TEMP TRIGGER _{tbl}_ut AFTER UPDATE ON {tbl} BEGIN\n"
-            sql += "  INSERT INTO undolog VALUES(NULL,"
-            sql += f"'UPDATE {tbl} "
-            sep = "SET "
+            if keep_log:
+                for tbl in args:
+                    self._drop_table_triggers(db, tbl)
+            for sql in sqllist:
+                db.execute(sql)
+        except BaseException:
+            db.execute('ROLLBACK TO undo_triggers')
+            db.execute('RELEASE undo_triggers')
+            raise
+        db.execute('RELEASE undo_triggers')
+
+    def _create_log(self, db):
+        """Create the undolog table, and the undostate table if persistent."""
+        if self._persistent:
+            db.execute(f"CREATE TABLE IF NOT EXISTS {self._log_schema}.{self._log_table}("
+                       "seq integer primary key, sql text)")
+            db.execute("CREATE TABLE IF NOT EXISTS"
+                       f" {self._log_schema}.{self._state_table}("
+                       "stack text, first integer, last integer)")
+        elif self._memory_log:
+            if 'undo_memory' not in _schemas(db):
+                db.execute("ATTACH DATABASE ':memory:' AS undo_memory")
+            db.execute(f"DROP TABLE IF EXISTS undo_memory.{self._log_table}")
+            db.execute(f"CREATE TABLE undo_memory.{self._log_table}("
+                       "seq integer primary key, sql text)")
+        else:
+            db.execute(f"DROP TABLE IF EXISTS temp.{self._log_table}")
+            db.execute(
+                f"CREATE TEMP TABLE {self._log_table}(seq integer primary key, sql text)")
+
+    @staticmethod
+    def _check_tables(db, tables):
+        """Raise an UnknownTableError if one of TABLES does not exist.
+
+        The tables of attached databases count too.  Like SQLite identifiers, names
+        are matched ignoring ASCII case.
+        """
+        q = " UNION ".join(f"SELECT name FROM \"{schema}\".sqlite_master"
+                           " WHERE type IN ('table','view')" for schema in _schemas(db))
+        known = {_fold_identifier(name) for (name,) in db.execute(q).fetchall()}
+        unknown = [tbl for tbl in tables if _fold_identifier(tbl) not in known]
+        if unknown:
+            raise UnknownTableError(f"no such table: {', '.join(unknown)}")
+
+    def _table_triggers(self, db, tbl):
+        """Return the statements creating the change recording triggers of TBL."""
+        excluded = self._excluded_columns.get(tbl, {})
+        # Generated columns (hidden 2 or 3) cannot be written back
+        info = db.execute(f"pragma table_xinfo({tbl})").fetchall()
+        if not info:
+            # table_xinfo is only known since SQLite 3.26.0
+            info = [col + (0,) for col in db.execute(f"pragma table_info({tbl})")]
+        info = [col[:6] for col in info if col[6] not in (2, 3)]
+        collist = []
+        for col in info:
+            (x1, name, x2, notnull, dflt_value, x5) = col
+            if name not in excluded:
+                collist.append(col)
+            elif excluded[name] is None and notnull and dflt_value is None:
+                raise UndoError(f"excluded column {tbl}.{name} is NOT NULL without"
+                                " a default and needs a placeholder")
+        placeholders = [(name, value) for (name, value) in excluded.items()
+                        if value is not None]
+
+        # Rows are located by rowid, by primary key in WITHOUT ROWID tables, or by
+        # all their columns in views
+        #
+        q = "SELECT 1 FROM sqlite_master WHERE type='view' AND name=?"
+        if db.execute(q, (tbl,)).fetchone():
+            key = [name for (x1, name, x2, x3, x4, x5) in collist]
+            (op, after, before) = (" IS ", "INSTEAD OF", "INSTEAD OF")
+        else:
+            try:
+                db.execute(f"SELECT rowid FROM {tbl} LIMIT 0")
+                key = None
+            except sqlite3.OperationalError:
+                key = [col[1] for col in sorted(info, key=lambda col: col[5]) if col[5]]
+            (op, after, before) = ("=", "AFTER", "BEFORE")
+
+        # Column names are quoted, as they may be keywords like "order", and
+        # written twice: in the trigger body, and in the undo SQL string literal
+        ident = _quote_identifier
+
+        def literal_ident(name):
+            return _quote_identifier(name).replace("'", "''")
+
+        # An INTEGER PRIMARY KEY column is an alias for the rowid
+        pk = [col for col in info if col[5]]
+        alias = None
+        if key is None and len(pk) == 1 and pk[0][2].upper() == "INTEGER":
+            alias = pk[0][1]
+
+        def where(ref):
+            if key is None:
+                return f" WHERE rowid='||{ref}.rowid"
+            return " WHERE " + "||' AND ".join(
+                f"{literal_ident(k)}{op}'||quote({ref}.{ident(k)})" for k in key)
+
+        prefix = self._trigger_prefix
+        sqllist = []
+        sql = f"CREATE TEMP TRIGGER {prefix}_{tbl}_it {after} INSERT ON {tbl} BEGIN\n"
+        sql += f"  INSERT INTO {self._log_table} VALUES(NULL,"
+        sql += f"'DELETE FROM {tbl}{where('new')});\nEND"
+        sqllist.append(sql)
+
+        # With every column excluded or generated, there is nothing to SET back
+        if collist:
+            sql = f"CREATE TEMP TRIGGER {prefix}_{tbl}_ut {after} UPDATE ON {tbl}"
+            if self._skip_noop_updates:
+                sql += " WHEN CASE WHEN " + " OR ".join(
+                    f"old.{ident(name)} IS NOT new.{ident(name)}"
+                    for (x1, name, x2, x3, x4, x5) in collist)
+                sql += f" THEN 1 ELSE {self._noop_function}() END"
+            sql += " BEGIN\n"
+            sql += f"  INSERT INTO {self._log_table} VALUES(NULL,"
+            sql += f"'UPDATE {tbl} SET "
+            # The row is located where the update moved it, and its rowid or key
+            # is set back with the other columns
+            if key is None and alias not in [col[1] for col in collist]:
+                sql += "'||CASE WHEN old.rowid=new.rowid THEN ''"
+                sql += " ELSE 'rowid='||old.rowid||',' END||'"
+            sep = ""
             for (x1, name, x2, x3, x4, x5) in collist:
-                sql += f"{sep}{name}='||quote(old.{name})||'"
+                sql += f"{sep}{literal_ident(name)}='||quote(old.{ident(name)})||'"
                 sep = ","
-            sql += " WHERE rowid='||old.rowid);\nEND;\n"
+            sql += f"{where('new')});\nEND"
+            sqllist.append(sql)
 
-            sql += f"CREATE TEMP TRIGGER _{tbl}_dt BEFORE DELETE ON {tbl} BEGIN\n"
-            sql += "  INSERT INTO undolog VALUES(NULL,"
-            sql += f"'INSERT INTO {tbl}(rowid"
-            for (x1, name, x2, x3, x4, x5) in collist:
-                sql += f",{name}"
-            sql += ") VALUES('||old.rowid||'"
-            for (x1, name, x2, x3, x4, x5) in collist:
-                sql += f",'||quote(old.{name})||'"
-            sql += ")');\nEND;\n"
+        sql = f"CREATE TEMP TRIGGER {prefix}_{tbl}_dt {before} DELETE ON {tbl} BEGIN\n"
+        sql += f"  INSERT INTO {self._log_table} VALUES(NULL,"
+        names = ["rowid"] if key is None else []
+        values = ["'||old.rowid||'"] if key is None else []
+        # The rowid alias is already listed as rowid
+        for (x1, name, x2, x3, x4, x5) in collist:
+            if name == alias:
+                continue
+            names.append(literal_ident(name))
+            values.append(f"'||quote(old.{ident(name)})||'")
+        for (name, value) in placeholders:
+            names.append(literal_ident(name))
+            values.append(f"'||quote({_sql_literal(value)})||'")
+        sql += f"'INSERT INTO {tbl}({','.join(names)}) VALUES({','.join(values)})');"
+        sql += "\nEND"
+        sqllist.append(sql)
 
-            db.executescript(sql)
+        q = "SELECT 1 FROM sqlite_master WHERE type='table' AND name=?" \
+            " AND sql LIKE '%AUTOINCREMENT%'"
+        if self._track_autoincrement and db.execute(q, (tbl,)).fetchone():
+            name = _sql_literal(tbl)
+            quoted = name.replace("'", "''")
+            # sqlite_sequence is only written at the end of the statement, so an
+            # AFTER trigger still sees the old value, and fires only for the rows
+            # actually inserted
+            sql = f"CREATE TEMP TRIGGER {prefix}_{tbl}_st AFTER INSERT ON {tbl} BEGIN\n"
+            sql += f"  INSERT INTO {self._log_table} SELECT NULL,coalesce(("
+            sql += "SELECT 'UPDATE sqlite_sequence SET seq='||seq||'"
+            sql += f" WHERE name={quoted}' FROM sqlite_sequence WHERE name={name}),"
+            sql += f"'DELETE FROM sqlite_sequence WHERE name={quoted}');\nEND"
+            sqllist.append(sql)
 
-    @staticmethod
-    def _drop_triggers(db):
-        """Drop all of the triggers that _create_triggers created."""
-        tlist = db.execute(
-            "SELECT name FROM sqlite_temp_master WHERE type='trigger'").fetchall()
-        for (trigger,) in tlist:
-            if not re.match("_.*_(i|u|d)t$", trigger):
-                continue
-            db.execute(f"DROP TRIGGER {trigger};")
+        return sqllist
+
+    def _drop_table_triggers(self, db, tbl):
+        """Drop the triggers that _table_triggers created for TBL."""
+        prefix = self._trigger_prefix
+        for kind in ('i', 'u', 'd', 's'):
+            trigger = f"{prefix}_{tbl}_{kind}t"
+            db.execute(f"DROP TRIGGER IF EXISTS {trigger}")
+
+    def _find_triggers(self, db, tables):
+        """Return the names of the temp triggers _create_triggers names for TABLES.
+
+        Like SQLite identifiers, the names are matched ignoring ASCII case.
+        """
         try:
-            db.execute("DROP TABLE undolog")
+            tlist = db.execute(
+                "SELECT name FROM sqlite_temp_schema WHERE type='trigger'").fetchall()
         except sqlite3.OperationalError:
-            pass
+            # sqlite_temp_schema is only known since SQLite 3.33.0
+            tlist = db.execute(
+                "SELECT name FROM sqlite_temp_master WHERE type='trigger'").fetchall()
+        names = {_fold_identifier(f"{self._trigger_prefix}_{tbl}_{kind}t")
+                 for tbl in tables for kind in ('i', 'u', 'd', 's')}
+        return [trigger for (trigger,) in tlist if _fold_identifier(trigger) in names]
+
+    def _drop_user_triggers(self):
+        """Drop the triggers of the recorded tables not created by _create_triggers.
+
+        Returns the SQL text creating them again, in their own schemas.
+        """
+        ours = set(self._find_triggers(self._db, self._tables))
+        tables = {_fold_identifier(tbl.split('.')[-1]) for tbl in self._tables}
+        user_triggers = []
+        for schema in _schemas(self._db):
+            q = f"SELECT name, tbl_name, sql FROM {schema}.sqlite_master" \
+                " WHERE type='trigger'"
+            for (name, tbl_name, sql) in self._db.execute(q).fetchall():
+                if (schema == 'temp' and name in ours
+                        or _fold_identifier(tbl_name) not in tables):
+                    continue
+                quoted = name.replace('"', '""')
+                self._db.execute(f'DROP TRIGGER {schema}."{quoted}"')
+                create = r'(?i)^CREATE\s+TRIGGER\s+(IF\s+NOT\s+EXISTS\s+)?'
+                user_triggers.append(re.sub(create, f'CREATE TRIGGER \\1{schema}.', sql))
+        return user_triggers
+
+    def _drop_triggers(self, db):
+        """Drop all of the triggers that _create_triggers created."""
+        for trigger in self._find_triggers(db, self._tables):
+            try:
+                db.execute(f"DROP TRIGGER IF EXISTS {trigger};")
+            except sqlite3.OperationalError as e:
+                # The table of the trigger may be in a database detached since
+                if not str(e).startswith(("no such table", "unknown database")):
+                    raise
+        db.execute(f"DROP TABLE IF EXISTS temp.{self._log_table}")
+
+    def _interval_tables(self, interval):
+        """Return the tables modified by replaying INTERVAL, in replay order."""
+        (begin, end) = interval
+        q1 = f"SELECT sql FROM {self._log_table} WHERE seq>={begin} AND seq<={end}" \
+             " ORDER BY seq DESC"
+        tables = []
+        for (sql,) in self._db.execute(q1).fetchall():
+            tbl = re.match("(?:DELETE FROM|UPDATE|INSERT INTO) ([^ (]+)", sql).group(1)
+            if tbl not in tables:
+                tables.append(tbl)
+        return tables
+
+    def _change_record(self, sql):
+        """Parse SQL, a statement written by the triggers, into a ChangeRecord."""
+        def record(op, tbl, pairs, rowid=None):
+            names = [_unquote_identifier(name) for (name, x) in pairs]
+            literals = [literal for (x, literal) in pairs]
+            columns = dict(zip(names, self._db.execute(
+                "SELECT " + ",".join(literals)).fetchone()))
+            rowid = columns.pop('rowid', rowid)
+            return ChangeRecord(table=tbl, op=op, rowid=rowid, columns=columns)
+
+        m = re.match(f"INSERT INTO ([^ (]+)\\(((?:(?:{_IDENTIFIER}),?)*)\\)"
+                     r" VALUES\((.*)\)$", sql, re.S)
+        if m:
+            names = re.findall(_IDENTIFIER, m.group(2))
+            pairs = zip(names, re.findall(_LITERAL, m.group(3)))
+            return record('INSERT', m.group(1), list(pairs))
+        m = re.match(r"DELETE FROM ([^ ]+) WHERE (.*)$", sql, re.S)
+        if m:
+            pairs = re.findall(f"({_IDENTIFIER})(?:=| IS )({_LITERAL})", m.group(2))
+            return record('DELETE', m.group(1), pairs)
+        m = re.match(f"UPDATE ([^ ]+) SET ((?:(?:{_IDENTIFIER})=(?:{_LITERAL}),?)+)"
+                     " WHERE (.*)$", sql, re.S)
+        pairs = re.findall(f"({_IDENTIFIER})=({_LITERAL})", m.group(2))
+        where = re.match(r"rowid=(-?\d+)$", m.group(3))
+        return record('UPDATE', m.group(1), pairs, int(where.group(1)) if where else None)
+
+    def _peek(self, stack):
+        """Return a copy of the top interval of STACK, or None."""
+        _undo = self._undo
+        if not _undo['active'] or not _undo[stack]:
+            return None
+        return tuple(_undo[stack][-1])
+
+    def _top_label(self, stack):
+        """Return the label of the top interval of STACK, or None."""
+        _undo = self._undo
+        if not _undo['active'] or not _undo[stack]:
+            return None
+        return _undo[stack][-1].label
+
+    def _thaw(self):
+        """Accept database changes into the undo stack."""
+        self._freeze_state = FreezeState.THAWED
+        self._freeze_seq = None
+
+    def _enable_recursive_triggers(self):
+        """Turn recursive_triggers on, remembering its value for deactivate.
+
+        The instances recording on the same connection share the setting, which
+        is only restored when the last of them deactivates.
+        """
+        # The instances collected without deactivating leave an empty set, which
+        # may belong to a closed connection whose id got reused
+        for key in [key for (key, (users, saved)) in _recursive_trigger_users.items()
+                    if not users]:
+            del _recursive_trigger_users[key]
+        entry = _recursive_trigger_users.get(id(self._db))
+        if entry is None:
+            saved = self._db.execute("pragma recursive_triggers").fetchone()[0]
+            self._db.execute("pragma recursive_triggers=1")
+            entry = _recursive_trigger_users[id(self._db)] = (weakref.WeakSet(), saved)
+        entry[0].add(self)
+        self._recursive_triggers = True
+
+    def _restore_recursive_triggers(self):
+        """Undo _enable_recursive_triggers for this instance.
+
+        The registry is cleaned up even if the connection was closed meanwhile.
+        """
+        if not self._recursive_triggers:
+            return
+        self._recursive_triggers = False
+        entry = _recursive_trigger_users.get(id(self._db))
+        if entry is None:
+            return
+        entry[0].discard(self)
+        if not entry[0]:
+            del _recursive_trigger_users[id(self._db)]
+            try:
+                self._db.execute(f"pragma recursive_triggers={entry[1]}")
+            except sqlite3.ProgrammingError:
+                # The connection was closed, taking the setting with it
+                pass
+
+    def _load_stacks(self):
+        """Read the undo/redo stacks back from the undostate table."""
+        _undo = self._undo
+        q = f"SELECT stack, first, last FROM {self._state_table} ORDER BY rowid"
+        rows = self._db.execute(q).fetchall()
+        for (stack, first, last) in rows:
+            _undo[stack].append(Interval(first, last))
+
+    def _save_stacks(self):
+        """Write the undo/redo stacks to the undostate table, if persistent."""
+        if not self._persistent:
+            return
+        _undo = self._undo
+        rows = [(stack, first, last)
+                for stack in ('undostack', 'redostack')
+                for (first, last) in _undo[stack]]
+        self._db.execute('SAVEPOINT undo_state')
+        self._db.execute(f"DELETE FROM {self._state_table}")
+        self._db.executemany(f"INSERT INTO {self._state_table} VALUES(?, ?, ?)", rows)
+        self._db.execute('RELEASE undo_state')
+        self._mark_changes()
+
+    def _evict_intervals(self):
+        """Drop the oldest undo intervals while undolog holds too many rows."""
+        _undo = self._undo
+        if self._max_total_rows is None:
+            return
+        q = f"SELECT count(*) FROM {self._log_table}"
+        while (_undo['undostack']
+               and self._db.execute(q).fetchone()[0] > self._max_total_rows):
+            (begin, end) = _undo['undostack'].pop(0)
+            self._dropped_intervals += 1
+            self._db.execute(
+                f"DELETE FROM {self._log_table} WHERE seq>={begin} AND seq<={end}")
+        self._start_interval()
+
+    def _discard_redo(self):
+        """Empty the redo stack, deleting its undolog rows and closing the gaps."""
+        _undo = self._undo
+        # Undo also leaves a gap where the rows of each step undone were
+        first = min(begin for (begin, end) in _undo['redostack'])
+        start = max([end for (begin, end) in _undo['undostack'] if end < first],
+                    default=0) + 1
+        pending = self._pending_changes()
+        for (begin, end) in _undo['redostack']:
+            self._db.execute(
+                f"DELETE FROM {self._log_table} WHERE seq>=? AND seq<=?", (begin, end))
+        self._skip_changes(pending)
+        _undo['redostack'] = []
+        # So that the undo steps merged across the gap still have a row per seq
+        self._renumber(start)
+
+    def _move_to_end(self, intervals):
+        """Move the rows of INTERVALS after the last undolog row, without gaps.
+
+        The pending changes are moved after them and the gaps closed.  Returns the
+        new first and last seqs of the rows of INTERVALS, which the caller must put
+        in place of the intervals on the undo stack.
+        """
+        _undo = self._undo
+        q = f"SELECT seq FROM {self._log_table} WHERE seq>=? AND seq<=? ORDER BY seq"
+        top = self.last_seq()
+        seqs = [seq for (begin, end) in intervals
+                for (seq,) in self._db.execute(q, (begin, end)).fetchall()]
+        pending_seqs = [seq for (seq,) in self._db.execute(
+            q, (_undo['firstlog'], top)).fetchall()]
+        pending = self._pending_changes()
+        self._db.execute('SAVEPOINT undo_move')
+        try:
+            for (new, old) in enumerate(seqs + pending_seqs, top + 1):
+                self._db.execute(
+                    f"UPDATE {self._log_table} SET seq={new} WHERE seq={old}")
+        except BaseException:
+            self._db.execute('ROLLBACK TO undo_move')
+            self._db.execute('RELEASE undo_move')
+            self._skip_changes(pending)
+            raise
+        self._db.execute('RELEASE undo_move')
+        self._skip_changes(pending)
+        moved = Interval(top + 1, top + len(seqs))
+        # Kept on the stack while renumbering, so that it gets renumbered too
+        _undo['undostack'].append(moved)
+        _undo['firstlog'] = top + len(seqs) + 1
+        if self._freeze_state is FreezeState.FROZEN:
+            self._freeze_seq = top + len(seqs) + len(
+                [seq for seq in pending_seqs if seq <= self._freeze_seq])
+        self._renumber(intervals[0][0])
+        _undo['undostack'].pop()
+        return (moved[0], moved[1])
+
+    def _renumber(self, start):
+        """Renumber the undolog seqs from START on without gaps, and the intervals.
+
+        The seqs below START are left as they are.
+        """
+        _undo = self._undo
+        seqs = [seq for (seq,) in self._db.execute(
+            f"SELECT seq FROM {self._log_table} WHERE seq>=? ORDER BY seq",
+            (start,)).fetchall()]
+
+        def before(seq):
+            return seq if seq < start else start + bisect.bisect_left(seqs, seq)
+
+        def upto(seq):
+            return seq if seq < start else start - 1 + bisect.bisect_right(seqs, seq)
+
+        pending = self._pending_changes()
+        self._db.execute('SAVEPOINT undo_compact')
+        try:
+            # Every row moves down, so renumbering in increasing order never collides
+            for (new, old) in enumerate(seqs, start):
+                if new != old:
+                    self._db.execute(
+                        f"UPDATE {self._log_table} SET seq={new} WHERE seq={old}")
+        except BaseException:
+            self._db.execute('ROLLBACK TO undo_compact')
+            self._db.execute('RELEASE undo_compact')
+            self._skip_changes(pending)
+            raise
+        self._db.execute('RELEASE undo_compact')
+        self._skip_changes(pending)
+        for stack in ('undostack', 'redostack'):
+            for interval in _undo[stack]:
+                interval[:] = [before(interval[0]), upto(interval[1])]
+        _undo['firstlog'] = before(_undo['firstlog'])
+        if self._freeze_state is FreezeState.FROZEN:
+            self._freeze_seq = upto(self._freeze_seq)
 
     def _start_interval(self):
         """Record the starting conditions of an undo interval."""
         _undo = self._undo
         _undo['firstlog'] = self._db.execute(
-            "SELECT coalesce(max(seq),0)+1 FROM undolog").fetchone()[0]
+            f"SELECT coalesce(max(seq),0)+1 FROM {self._log_table}").fetchone()[0]
+        self._mark_changes()
+
+    def _mark_changes(self):
+        """Remember the total_changes of the connection, in strict mode."""
+        self._total_changes = self._db.total_changes if self._strict else None
+
+    def _pending_changes(self):
+        """Return the changes counted since _mark_changes, or None if not checked."""
+        if self._total_changes is None:
+            return None
+        return self._db.total_changes - self._total_changes
+
+    def _skip_changes(self, pending):
+        """Leave the changes made since _pending_changes returned PENDING unchecked.
+
+        Unlike _mark_changes, the changes made before them are still checked.
+        """
+        if pending is not None and self._total_changes is not None:
+            self._total_changes = self._db.total_changes - pending
+
+    def _count_noop_update(self):
+        """Leave an UPDATE skipped by skip_noop_updates out of the strict-mode count.
+
+        Called by the update triggers for the rows they do not record; returns 0.
+        """
+        if self._total_changes is not None:
+            self._total_changes += 1
+        return 0
+
+    def _untracked_changes(self, begin, end):
+        """Return how many changes since BEGIN were not logged up to seq END.
+
+        Every recorded change counts twice in total_changes, once for the row
+        and once for its undolog row, except the sqlite_sequence rows of
+        track_autoincrement, which only count once.  The rows deleted by REPLACE
+        with track_replace are logged but not counted at all, so they may hide
+        other changes.
+        Returns 0 when the interval is not checked.
+        """
+        if not self._strict or self._total_changes is None:
+            return 0
+        logged = max(end - begin + 1, 0)
+        recorded = logged
+        if self._track_autoincrement and logged:
+            q = f"SELECT count(*) FROM {self._log_table} WHERE seq>=? AND seq<=?" \
+                " AND (sql LIKE 'UPDATE sqlite_sequence %'" \
+                " OR sql LIKE 'DELETE FROM sqlite_sequence %')"
+            recorded -= self._db.execute(q, (begin, end)).fetchone()[0]
+        return max(self._db.total_changes - self._total_changes - logged - recorded, 0)
+
+    def _steps(self, v1, v2, count):
+        """Do COUNT steps of undo or redo, all or none of them.
+
+        V1 and V2 are as for _step.  Returns the number of statements replayed.
+        """
+        _undo = self._undo
+        saved = (list(_undo[v1]), list(_undo[v2]), _undo['firstlog'])
+        replayed = 0
+        changes = 0
+        self._db.execute('SAVEPOINT undo_steps')
+        try:
+            for x in range(count):
+                replayed += self._step(v1, v2)
+                changes += self._last_step_changes
+        except BaseException:
+            self._db.execute('ROLLBACK TO undo_steps')
+            self._db.execute('RELEASE undo_steps')
+            (_undo[v1], _undo[v2], _undo['firstlog']) = saved
+            self._save_stacks()
+            raise
+        self._db.execute('RELEASE undo_steps')
+        self._last_step_changes = changes
+        return replayed
 
     def _step(self, v1, v2):
         """Do a single step of undo or redo.
 
         For an undo V1=="undostack" and V2=="redostack".  For a redo,
-        V1=="redostack" and V2=="undostack".
+        V1=="redostack" and V2=="undostack".  Returns the number of statements
+        replayed.
+
+        A CorruptLogError is raised, leaving the stacks unchanged, if the rows of the
+        interval were deleted from undolog behind our back.  If a statement fails,
+        the step is rolled back and the stacks are left unchanged as well.  An
+        UndoError is raised while frozen, as unfreeze would delete the rows the
+        step records.
+
+        The step runs in a savepoint, so it can be nested in a transaction opened
+        by the application.
         """
+        if self._freeze_state is FreezeState.FROZEN:
+            raise UndoError("cannot undo or redo while frozen")
         _undo = self._undo
         op = _undo[v1][-1]
-        _undo[v1] = _undo[v1][0:-1]
         (begin, end) = op
-        self._db.execute('BEGIN')
-        q1 = f"SELECT sql FROM undolog WHERE seq>={begin} AND seq<={end}" \
+        q1 = f"SELECT sql FROM {self._log_table} WHERE seq>={begin} AND seq<={end}" \
              " ORDER BY seq DESC"
         sqllist = self._db.execute(q1).fetchall()
-        self._db.execute(f"DELETE FROM undolog WHERE seq>={begin} AND seq<={end}")
-        _undo['firstlog'] = self._db.execute(
-            "SELECT coalesce(max(seq),0)+1 FROM undolog").fetchone()[0]
-        for (sql,) in sqllist:
-            self._db.execute(sql)
-        self._db.execute('COMMIT')
-        # self.reload_all()
+        if not sqllist:
+            raise CorruptLogError(f"undolog has no rows for the interval {begin}-{end}")
+        saved = (_undo[v1], _undo['firstlog'])
+        _undo[v1] = _undo[v1][0:-1]
+        self._stepping = True
+        pending = self._pending_changes()
+        try:
+            self._db.execute('SAVEPOINT undo_step')
+            try:
+                self._db.execute(
+                    f"DELETE FROM {self._log_table} WHERE seq>={begin} AND seq<={end}")
+                _undo['firstlog'] = self._db.execute(
+                    f"SELECT coalesce(max(seq),0)+1 FROM {self._log_table}"
+                ).fetchone()[0]
+                user_triggers = []
+                if not self._replay_user_triggers:
+                    user_triggers = self._drop_user_triggers()
+                changes = 0
+                for (done, (sql,)) in enumerate(sqllist, 1):
+                    if self._replay_callback is not None:
+                        self._replay_callback(sql)
+                    changes += self._db.execute(sql).rowcount
+                    if self._progress_callback is not None and (
+                            done % self._progress_every == 0 or done == len(sqllist)):
+                        self._progress_callback(done, len(sqllist))
+                for sql in user_triggers:
+                    self._db.execute(sql)
+            except BaseException:
+                self._db.execute('ROLLBACK TO undo_step')
+                self._db.execute('RELEASE undo_step')
+                (_undo[v1], _undo['firstlog']) = saved
+                # The rolled back changes still count in total_changes
+                self._skip_changes(pending)
+                raise
+            self._db.execute('RELEASE undo_step')
+            self._last_step_changes = changes
+            # self.reload_all()
 
-        end = self._db.execute("SELECT coalesce(max(seq),0) FROM undolog").fetchone()[0]
-        begin = _undo['firstlog']
-        _undo[v2].append([begin, end])
+            end = self._db.execute(
+                f"SELECT coalesce(max(seq),0) FROM {self._log_table}").fetchone()[0]
+            begin = _undo['firstlog']
+            moved = Interval(begin, end, op.label, op.at)
+            moved._id = op._id
+            _undo[v2].append(moved)
+        finally:
+            self._stepping = False
+        self._last_barrier = None
         self._start_interval()
+        self._save_stacks()
         # self.refresh()
+        return len(sqllist)
+
+
+class SQLiteUndoRedoBuilder:
+    """Fluent configuration of a SQLiteUndoRedo:
+
+        sqlur = (SQLiteUndoRedo.builder(db)
+                 .log_table('doclog')
+                 .max_total_rows(10000)
+                 .coalesce(0.3)
+                 .build())
+
+    Each method returns the builder.  Options not given keep the defaults of the
+    constructor and setters: a temporary undolog in the main schema, no trigger
+    prefix, no row limit, no coalescing, not strict and no callbacks.
+    """
+
+    def __init__(self, cls, db):
+        self._cls = cls
+        self._db = db
+        self._kwargs = {}
+        self._setters = []
+
+    def persistent(self, persistent=True):
+        """See the PERSISTENT argument of SQLiteUndoRedo."""
+        self._kwargs['persistent'] = persistent
+        return self
+
+    def log_schema(self, schema):
+        """See the LOG_SCHEMA argument of SQLiteUndoRedo."""
+        self._kwargs['log_schema'] = schema
+        return self
+
+    def memory_log(self, memory_log=True):
+        """See the MEMORY_LOG argument of SQLiteUndoRedo."""
+        self._kwargs['memory_log'] = memory_log
+        return self
+
+    def log_table(self, name):
+        """See the LOG_TABLE argument of SQLiteUndoRedo."""
+        self._kwargs['log_table'] = name
+        return self
+
+    def trigger_prefix(self, prefix):
+        """See the TRIGGER_PREFIX argument of SQLiteUndoRedo."""
+        self._kwargs['trigger_prefix'] = prefix
+        return self
+
+    def track_autoincrement(self, track=True):
+        """See the TRACK_AUTOINCREMENT argument of SQLiteUndoRedo."""
+        self._kwargs['track_autoincrement'] = track
+        return self
+
+    def skip_noop_updates(self, skip=True):
+        """See the SKIP_NOOP_UPDATES argument of SQLiteUndoRedo."""
+        self._kwargs['skip_noop_updates'] = skip
+        return self
+
+    def track_replace(self, track=True):
+        """See the TRACK_REPLACE argument of SQLiteUndoRedo."""
+        self._kwargs['track_replace'] = track
+        return self
+
+    def max_total_rows(self, n):
+        """See SQLiteUndoRedo.set_max_total_rows."""
+        self._setters.append(lambda sqlur: sqlur.set_max_total_rows(n))
+        return self
+
+    def coalesce(self, seconds):
+        """See SQLiteUndoRedo.set_coalesce_window."""
+        self._setters.append(lambda sqlur: sqlur.set_coalesce_window(seconds))
+        return self
+
+    def strict(self, strict=True):
+        """See SQLiteUndoRedo.set_strict."""
+        self._setters.append(lambda sqlur: sqlur.set_strict(strict))
+        return self
+
+    def on_replay(self, callback):
+        """See SQLiteUndoRedo.on_replay."""
+        self._setters.append(lambda sqlur: sqlur.on_replay(callback))
+        return self
+
+    def build(self):
+        """Create the SQLiteUndoRedo, not activated yet."""
+        sqlur = self._cls(self._db, **self._kwargs)
+        for setter in self._setters:
+            setter(sqlur)
+        return sqlur
+
+
+class SharedSQLiteUndoRedo:
+    """SQLiteUndoRedo that can be shared between threads.
+
+    Every use of the undo/redo system, and of the database changes it records,
+    must happen while holding the lock:
+
+        with shared.lock() as sqlur:
+            db.execute(...)
+            sqlur.barrier()
+
+    The connection has to be opened with check_same_thread=False.
+    """
+
+    def __init__(self, db):
+        self._sqlur = SQLiteUndoRedo(db)
+        self._lock = threading.Lock()
+
+    @contextlib.contextmanager
+    def lock(self):
+        """Acquire the lock and return the wrapped SQLiteUndoRedo."""
+        with self._lock:
+            yield self._sqlur
+
+
+class AsyncSQLiteUndoRedo:
+    """SQLiteUndoRedo for asyncio code, run off the event loop.
+
+    Every use of the undo/redo system, and of the database changes it records,
+    runs in a single worker thread, so the stacks and the connection are never
+    used by two threads at once:
+
+        await async_sqlur.execute("INSERT INTO tbl VALUES(?)", (value,))
+        await async_sqlur.barrier()
+        await async_sqlur.undo()
+
+    The connection has to be opened with check_same_thread=False.  EXECUTOR
+    defaults to a ThreadPoolExecutor with one worker, shut down by close.
+    """
+
+    def __init__(self, db, executor=None):
+        self._db = db
+        self._sqlur = SQLiteUndoRedo(db)
+        self._owns_executor = executor is None
+        self._executor = executor or concurrent.futures.ThreadPoolExecutor(1)
+
+    async def run(self, func, *args):
+        """Return the result of FUNC(sqlur, *ARGS) called in the worker thread."""
+        loop = asyncio.get_event_loop()
+        return await loop.run_in_executor(self._executor, func, self._sqlur, *args)
+
+    async def execute(self, sql, parameters=()):
+        """Run SQL on the connection in the worker thread and return all its rows."""
+        return await self.run(lambda sqlur: self._db.execute(sql, parameters).fetchall())
+
+    async def activate(self, *args):
+        await self.run(SQLiteUndoRedo.activate, *args)
+
+    async def deactivate(self):
+        await self.run(SQLiteUndoRedo.deactivate)
+
+    async def barrier(self):
+        await self.run(SQLiteUndoRedo.barrier)
+
+    async def undo(self):
+        return await self.run(SQLiteUndoRedo.undo)
+
+    async def redo(self):
+        return await self.run(SQLiteUndoRedo.redo)
+
+    def close(self):
+        """Shut down the executor created by the constructor, if any."""
+        if self._owns_executor:
+            self._executor.shutdown()
@@ -12,12 +12,23 @@
 # See the License for the specific language governing permissions and
 # limitations under the License.
 
+import asyncio
+import json
+import os
+import random
 import sqlite3
+import sys
+import tempfile
+import threading
 import unittest
 
 from unittest import mock
 
-from sqlite_undoredo import SQLiteUndoRedo
+from sqlite_undoredo import (
+    AsyncSQLiteUndoRedo, BoundaryState, ChangeRecord, CorruptLogError, FreezeState,
+    ReadOnlyError, SavedUndoState, SQLiteUndoRedo, SharedSQLiteUndoRedo, UndoError,
+    UnknownTableError, UntrackedWriteError, apply_changelog, undo_squashed,
+)
 
 
 class SQLiteUndoRedoTest(unittest.TestCase):
@@ -43,7 +54,7 @@ class SQLiteUndoRedoTest(unittest.TestCase):
         self.assertEqual(self.sqlur._undo['undostack'], [])
         self.assertEqual(self.sqlur._undo['redostack'], [])
         self.assertEqual(self.sqlur._undo['active'], 1)
-        self.assertEqual(self.sqlur._undo['freeze'], -1)
+        self.assertIs(self.sqlur._freeze_state, FreezeState.THAWED)
 
         mock_start_interval.assert_called_with()
 
@@ -57,10 +68,18 @@ class SQLiteUndoRedoTest(unittest.TestCase):
         self.assertEqual(self.sqlur._undo['undostack'], [])
         self.assertEqual(self.sqlur._undo['redostack'], [])
         self.assertEqual(self.sqlur._undo['active'], 1)
-        self.assertEqual(self.sqlur._undo['freeze'], -1)
+        self.assertIs(self.sqlur._freeze_state, FreezeState.THAWED)
 
         mock_start_interval.assert_called_with()
 
+    def test_activate_unpacked_tables(self):
+        tables = (name for name in ('tbl1', 'tbl2'))
+
+        self.sqlur.activate(*tables)
+
+        self.assertEqual(len(self._get_triggers(self.test_db)), 6)
+        self.assertEqual(self.sqlur._tables, ['tbl1', 'tbl2'])
+
     def test_activate_while_active(self):
         self.assertEqual(self.sqlur._undo['active'], 0)
         self.sqlur.activate()
@@ -74,6 +93,33 @@ class SQLiteUndoRedoTest(unittest.TestCase):
         mock_start_interval.assert_not_called()
         self.assertEqual(self.sqlur._undo['active'], 1)
 
+    def test_activate_leftover_triggers(self):
+        other = SQLiteUndoRedo.activated(self.test_db, ['tbl1'])
+
+        with self.assertRaises(UndoError):
+            self.sqlur.activate('tbl2', 'TBL1')
+
+        self.assertEqual(self.sqlur._undo['active'], 0)
+        self.assertEqual(other._undo['active'], 1)
+        self.assertEqual(len(self._get_triggers(self.test_db)), 3)
+
+    def test_activate_other_triggers(self):
+        self.test_db.execute("CREATE TEMP TRIGGER _audit_it AFTER INSERT ON tbl1 BEGIN"
+                             " SELECT 1; END")
+        other = SQLiteUndoRedo.activated(self.test_db, ['tbl1'], log_table='doclog',
+                                         trigger_prefix='_doc')
+
+        self.sqlur.activate('tbl2')
+
+        self.assertEqual(self.sqlur._undo['active'], 1)
+
+        self.sqlur.deactivate()
+
+        self.assertEqual(
+            self._get_triggers(self.test_db),
+            [('_audit_it',), ('_doc_tbl1_it',), ('_doc_tbl1_ut',), ('_doc_tbl1_dt',)])
+        self.assertEqual(other._undo['active'], 1)
+
     def test_deactivate(self):
         self.sqlur.activate('tbl1')
 
@@ -85,7 +131,59 @@ class SQLiteUndoRedoTest(unittest.TestCase):
         self.assertEqual(self.sqlur._undo['undostack'], [])
         self.assertEqual(self.sqlur._undo['redostack'], [])
         self.assertEqual(self.sqlur._undo['active'], 0)
-        self.assertEqual(self.sqlur._undo['freeze'], -1)
+        self.assertIs(self.sqlur._freeze_state, FreezeState.INACTIVE)
+
+    def test_deactivate_squashing(self):
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (1,))
+        self.sqlur.activate('tbl1', 'tbl2')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("UPDATE tbl1 SET a=?", (42,))
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (69,))
+        self.sqlur.barrier()
+        self.test_db.execute("DELETE FROM tbl1 WHERE rowid=?", (1,))
+        self.sqlur.barrier()
+        self.sqlur.undo()
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (404,))
+
+        self.assertEqual(self.sqlur.deactivate_squashing(), 5)
+
+        self.assertEqual(self.sqlur._undo['active'], 0)
+        self.assertEqual(self._get_triggers(self.test_db), [])
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(42,), (42,)])
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl2").fetchall(),
+                         [(69,), (404,)])
+
+        self.assertEqual(undo_squashed(self.test_db), 5)
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(1,)])
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl2").fetchall(), [])
+        q = "SELECT 1 FROM sqlite_master WHERE name='undo_squashed'"
+        self.assertIsNone(self.test_db.execute(q).fetchone())
+
+    def test_deactivate_after_detach(self):
+        self.test_db.execute("ATTACH DATABASE ':memory:' AS aux")
+        self.test_db.execute("CREATE TABLE aux.items(a)")
+        self.sqlur.activate('tbl1', 'items')
+        self.test_db.execute("INSERT INTO items VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("DETACH DATABASE aux")
+
+        self.sqlur.deactivate()
+
+        self.assertEqual(self.sqlur._undo['active'], 0)
+        self.assertEqual(self._get_triggers(self.test_db), [])
+
+    def test_deactivate_memory_log_after_detach(self):
+        sqlur = SQLiteUndoRedo(self.test_db, memory_log=True)
+        sqlur.activate('tbl1')
+        sqlur._drop_triggers(self.test_db)
+        self.test_db.execute("DETACH DATABASE undo_memory")
+
+        sqlur.deactivate()
+
+        self.assertEqual(sqlur._undo['active'], 0)
 
     def test_deactivate_while_not_active(self):
         self.assertEqual(self.sqlur._undo['active'], 0)
@@ -95,24 +193,227 @@ class SQLiteUndoRedoTest(unittest.TestCase):
 
         mock_drop_triggers.assert_not_called()
 
+    def test_reset(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+        self.sqlur.barrier()
+        self.sqlur.undo()
+        self.sqlur.freeze()
+
+        self.sqlur.reset()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [])
+        self.assertEqual(self.sqlur._undo['redostack'], [])
+        self.assertIs(self.sqlur._freeze_state, FreezeState.THAWED)
+        self.assertEqual(self.sqlur._undo['firstlog'], 1)
+        self.assertEqual(self.test_db.execute("SELECT * FROM undolog").fetchall(), [])
+        self.assertEqual(len(self._get_triggers(self.test_db)), 3)
+
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (69,))
+        self.sqlur.barrier()
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(23,)])
+
+    def test_reset_while_not_active(self):
+        with mock.patch.object(self.sqlur, '_db') as mock_db:
+            self.sqlur.reset()
+
+        mock_db.execute.assert_not_called()
+
+    def test_clear_redo(self):
+        self.sqlur.activate('tbl1')
+        for value in (23, 42, 69):
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (value,))
+            self.sqlur.barrier()
+        self.sqlur.undo()
+        self.sqlur.undo()
+
+        self.sqlur.clear_redo()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+        self.assertEqual(self.sqlur._undo['redostack'], [])
+        self.assertEqual(self.sqlur._undo['firstlog'], 2)
+        self.assertEqual(self.test_db.execute("SELECT seq FROM undolog").fetchall(),
+                         [(1,)])
+        self.assertTrue(self.sqlur.boundary_state().at_newest)
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(23,)])
+
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (404,))
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [2, 2]])
+
+        self.sqlur.undo()
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [])
+
+    def test_clear_redo_pending_changes(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.sqlur.undo()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+
+        self.sqlur.clear_redo()
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [])
+
+    def test_clear_redo_strict(self):
+        self.sqlur.set_strict(True)
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.sqlur.undo()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+
+        self.sqlur.clear_redo()
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+
+        self.sqlur.undo()
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (404,))
+        self.sqlur.clear_redo()
+
+        with self.assertRaises(UntrackedWriteError):
+            self.sqlur.barrier()
+
+    def test_shrink_memory(self):
+        self.sqlur.activate('tbl1')
+        self.sqlur.set_max_total_rows(2)
+        for value in (23, 42, 69):
+            self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(value,), (-value,)])
+            self.sqlur.barrier_labeled(f"insert {value}")
+        undostack = self.sqlur._undo['undostack']
+
+        self.sqlur.shrink_memory()
+
+        self.assertIsNot(self.sqlur._undo['undostack'], undostack)
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 2]])
+        self.assertEqual(self.sqlur.undo_label(), 'insert 69')
+        self.assertEqual(self.sqlur._undo['firstlog'], 3)
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT a FROM tbl1").fetchall(),
+                         [(23,), (-23,), (42,), (-42,)])
+
+    def test_compact(self):
+        self.sqlur.activate('tbl1')
+        self.sqlur.set_max_total_rows(4)
+        for value in (23, 42, 69):
+            self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(value,), (-value,)])
+            self.sqlur.barrier()
+        self.sqlur.undo()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[3, 4]])
+        self.assertEqual(self.sqlur._undo['redostack'], [[5, 6]])
+
+        self.sqlur.compact()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 2]])
+        self.assertEqual(self.sqlur._undo['redostack'], [[3, 4]])
+        self.assertEqual(self.sqlur._undo['firstlog'], 5)
+        self.assertEqual(self.test_db.execute("SELECT seq FROM undolog").fetchall(),
+                         [(1,), (2,), (3,), (4,)])
+
+        self.sqlur.redo()
+
+        self.assertEqual(self.test_db.execute("SELECT a FROM tbl1").fetchall(),
+                         [(23,), (-23,), (42,), (-42,), (69,), (-69,)])
+
+        self.sqlur.undo()
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT a FROM tbl1").fetchall(),
+                         [(23,), (-23,)])
+
+    def test_compact_strict(self):
+        self.sqlur.set_strict(True)
+        self.sqlur.activate('tbl1')
+        self.sqlur.set_max_total_rows(1)
+        for value in (23, 42):
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (value,))
+            self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (69,))
+
+        self.sqlur.compact()
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[2, 2]])
+
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (404,))
+        self.sqlur.compact()
+
+        with self.assertRaises(UntrackedWriteError):
+            self.sqlur.barrier()
+
+    def test_compact_error(self):
+        self.sqlur.activate('tbl1')
+        self.sqlur.set_max_total_rows(2)
+        for value in (23, 42, 69):
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (value,))
+            self.sqlur.barrier()
+        self.test_db.execute(
+            "CREATE TEMP TRIGGER fail BEFORE UPDATE ON undolog WHEN new.seq=2"
+            " BEGIN SELECT raise(ABORT, 'fail'); END")
+
+        with self.assertRaises(sqlite3.IntegrityError):
+            self.sqlur.compact()
+
+        self.assertEqual(self.test_db.execute("SELECT seq FROM undolog").fetchall(),
+                         [(2,), (3,)])
+        self.assertEqual(self.sqlur._undo['undostack'], [[2, 2], [3, 3]])
+
     def test_freeze(self):
         self.sqlur.activate('tbl1')
-        self.assertEqual(self.sqlur._undo['freeze'], -1)
+        self.assertIs(self.sqlur._freeze_state, FreezeState.THAWED)
 
         self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
         self.sqlur.barrier()
 
         self.sqlur.freeze()
 
-        self.assertEqual(self.sqlur._undo['freeze'], 2)
+        self.assertIs(self.sqlur._freeze_state, FreezeState.FROZEN)
+        self.assertEqual(self.sqlur._freeze_seq, 2)
+
+    def test_freeze_undo(self):
+        self.sqlur.activate('tbl1')
+        for value in (23, 42):
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (value,))
+            self.sqlur.barrier()
+        self.sqlur.freeze()
+
+        with self.assertRaises(UndoError):
+            self.sqlur.undo()
+
+        self.sqlur.unfreeze()
+
+        self.assertTrue(self.sqlur.verify())
+
+        self.sqlur.undo()
+        self.sqlur.undo()
+        self.sqlur.redo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(23,)])
 
     def test_freeze_while_frozen(self):
         self.sqlur.activate('tbl1')
-        self.assertEqual(self.sqlur._undo['freeze'], -1)
+        self.assertIs(self.sqlur._freeze_state, FreezeState.THAWED)
 
         self.sqlur.freeze()
 
-        self.assertEqual(self.sqlur._undo['freeze'], 0)
+        self.assertIs(self.sqlur._freeze_state, FreezeState.FROZEN)
+        self.assertEqual(self.sqlur._freeze_seq, 0)
 
         with self.assertRaises(Exception):
             self.sqlur.freeze()
@@ -132,15 +433,50 @@ class SQLiteUndoRedoTest(unittest.TestCase):
 
         self.assertEqual(mock_db.execute.call_count, 1)
 
+    def test_freeze_at(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(42,), (69,)])
+
+        self.sqlur.freeze_at(2)
+
+        self.assertEqual(self.sqlur.freeze_point(), 2)
+
+        self.sqlur.unfreeze()
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [2, 2]])
+        self.assertEqual(len(self.test_db.execute("SELECT * FROM undolog").fetchall()), 2)
+
+    def test_freeze_at_out_of_range(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+
+        with self.assertRaises(UndoError):
+            self.sqlur.freeze_at(0)
+
+        with self.assertRaises(UndoError):
+            self.sqlur.freeze_at(3)
+
+        self.assertIsNone(self.sqlur.freeze_point())
+
+        self.sqlur.freeze_at(1)
+
+        self.assertEqual(self.sqlur.freeze_point(), 1)
+
     def test_unfreeze(self):
         self.sqlur.activate('tbl1')
-        self.assertEqual(self.sqlur._undo['freeze'], -1)
+        self.assertIs(self.sqlur._freeze_state, FreezeState.THAWED)
 
         self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
         self.sqlur.barrier()
 
         self.sqlur.freeze()
-        self.assertEqual(self.sqlur._undo['freeze'], 2)
+        self.assertIs(self.sqlur._freeze_state, FreezeState.FROZEN)
+        self.assertEqual(self.sqlur._freeze_seq, 2)
 
         self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(69,), (404,)])
         self.sqlur.barrier()
@@ -151,7 +487,19 @@ class SQLiteUndoRedoTest(unittest.TestCase):
 
         self.assertEqual(len(self.test_db.execute("SELECT * FROM undolog").fetchall()), 2)
 
-        self.assertEqual(self.sqlur._undo['freeze'], -1)
+        self.assertIs(self.sqlur._freeze_state, FreezeState.THAWED)
+
+    def test_freeze_after_deactivate(self):
+        self.sqlur.activate('tbl1')
+        self.sqlur.deactivate()
+
+        self.assertIs(self.sqlur._freeze_state, FreezeState.INACTIVE)
+
+        self.sqlur.freeze()
+        self.sqlur.unfreeze()
+
+        self.assertIs(self.sqlur._freeze_state, FreezeState.INACTIVE)
+        self.assertIsNone(self.sqlur.freeze_point())
 
     def test_unfreeze_before_activate(self):
         self.assertEqual(self.sqlur._undo['active'], 0)
@@ -171,82 +519,1818 @@ class SQLiteUndoRedoTest(unittest.TestCase):
 
     def test_unfreeze_while_not_frozen(self):
         self.sqlur.activate('tbl1')
-        self.assertEqual(self.sqlur._undo['freeze'], -1)
+        self.assertIs(self.sqlur._freeze_state, FreezeState.THAWED)
 
         with self.assertRaises(Exception):
             self.sqlur.unfreeze()
 
-    def test_barrier(self):
+    def test_freeze_point(self):
+        self.assertIsNone(self.sqlur.freeze_point())
+
         self.sqlur.activate('tbl1')
 
+        self.assertIsNone(self.sqlur.freeze_point())
+
         self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
-        self.sqlur.barrier()
-        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
-        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+        self.sqlur.freeze()
 
-        self.sqlur.barrier()
+        self.assertEqual(self.sqlur.freeze_point(), 1)
 
-        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [2, 2]])
+        self.sqlur.unfreeze()
 
-    def test_barrier_several_changes(self):
-        self.sqlur.activate('tbl1')
+        self.assertIsNone(self.sqlur.freeze_point())
 
+    def test_frozen(self):
+        self.sqlur.activate('tbl1')
         self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
-        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
-
         self.sqlur.barrier()
 
-        self.assertEqual(self.sqlur._undo['undostack'], [[1, 2]])
+        with self.sqlur.frozen():
+            self.assertIs(self.sqlur._freeze_state, FreezeState.FROZEN)
+            self.assertEqual(self.sqlur._freeze_seq, 1)
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
 
-    def test_barrier_while_not_active(self):
-        self.assertEqual(self.sqlur._undo['active'], 0)
+        self.assertIs(self.sqlur._freeze_state, FreezeState.THAWED)
+        self.assertEqual(len(self.test_db.execute("SELECT * FROM undolog").fetchall()), 1)
 
-        with mock.patch.object(self.sqlur, '_db') as mock_db:
-            self.sqlur.barrier()
+    def test_frozen_early_exit(self):
+        self.sqlur.activate('tbl1')
 
-        mock_db.execute.assert_not_called()
+        def edit():
+            with self.sqlur.frozen():
+                self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+                return
+                self.fail()
 
-        self.sqlur.activate()
+        edit()
 
-        with mock.patch.object(self.sqlur, '_db') as mock_db:
-            self.sqlur.barrier()
+        self.assertIs(self.sqlur._freeze_state, FreezeState.THAWED)
 
-        self.assertEqual(mock_db.execute.call_count, 2)
+        with self.assertRaises(ZeroDivisionError):
+            with self.sqlur.frozen():
+                1 / 0
 
-    def test_barrier_while_frozen(self):
-        self.sqlur.activate('tbl1')
+        self.assertIs(self.sqlur._freeze_state, FreezeState.THAWED)
+        self.assertEqual(self.test_db.execute("SELECT * FROM undolog").fetchall(), [])
 
+    def test_freeze_table(self):
+        self.sqlur.activate('tbl1', 'tbl2')
+
+        self.sqlur.freeze_table('tbl2')
         self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (42,))
         self.sqlur.barrier()
-        self.sqlur.freeze()
-        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
 
+        self.assertEqual(self._get_triggers(self.test_db),
+                         [('_tbl1_it',), ('_tbl1_ut',), ('_tbl1_dt',)])
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [])
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl2").fetchall(), [(42,)])
+
+    def test_freeze_table_not_tracked(self):
+        self.sqlur.activate('tbl1')
+
+        with self.assertRaises(UndoError):
+            self.sqlur.freeze_table('tbl2')
+
+    def test_freeze_table_while_frozen(self):
+        self.sqlur.activate('tbl1')
+        self.sqlur.freeze_table('tbl1')
+
+        with self.assertRaises(UndoError):
+            self.sqlur.freeze_table('tbl1')
+
+    def test_unfreeze_table(self):
+        self.sqlur.activate('tbl1', 'tbl2')
+        self.sqlur.freeze_table('tbl2')
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (23,))
+
+        self.sqlur.unfreeze_table('tbl2')
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (42,))
         self.sqlur.barrier()
+        self.sqlur.undo()
 
-        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [2, 1]])
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl2").fetchall(), [(23,)])
 
-    def test_barrier_after_no_changes(self):
+    def test_unfreeze_table_in_transaction(self):
+        self.sqlur.activate('tbl1', 'tbl2')
+        self.sqlur.freeze_table('tbl2')
+        self.test_db.execute("BEGIN")
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+
+        self.sqlur.unfreeze_table('tbl2')
+
+        self.assertTrue(self.test_db.in_transaction)
+
+        self.test_db.execute("ROLLBACK")
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [])
+        self.assertEqual(len(self._get_triggers(self.test_db)), 3)
+
+    def test_unfreeze_table_while_not_frozen(self):
         self.sqlur.activate('tbl1')
 
+        with self.assertRaises(UndoError):
+            self.sqlur.unfreeze_table('tbl1')
+
+    def test_track_table(self):
+        self.sqlur.activate('tbl1')
         self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
         self.sqlur.barrier()
-        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+        self.test_db.execute("ALTER TABLE tbl1 ADD COLUMN c")
 
-        self.sqlur.barrier()
+        self.sqlur.track_table('tbl1')
+        self.sqlur.track_table('tbl2')
 
-        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+        self.assertEqual(self.sqlur._tables, ['tbl1', 'tbl2'])
+        self.assertEqual(len(self._get_triggers(self.test_db)), 6)
 
-    def test_undo(self):
-        with mock.patch.object(self.sqlur, '_step') as mock_step:
-            self.sqlur.undo()
+        self.test_db.execute("UPDATE tbl1 SET a=?, c=?", (42, 'x'))
+        self.sqlur.barrier()
+        self.test_db.execute("DELETE FROM tbl1")
+        self.sqlur.barrier()
 
-        mock_step.assert_called_with('undostack', 'redostack')
+        self.sqlur.undo()
 
-    def test_redo(self):
-        with mock.patch.object(self.sqlur, '_step') as mock_step:
-            self.sqlur.redo()
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(42, 'x')])
 
-        mock_step.assert_called_with('redostack', 'undostack')
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(23, None)])
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [])
+
+    def test_track_table_while_not_active(self):
+        with self.assertRaises(UndoError):
+            self.sqlur.track_table('tbl1')
+
+    def test_is_tracking(self):
+        self.assertFalse(self.sqlur.is_tracking('tbl1'))
+
+        self.sqlur.activate('tbl1', 'tbl2')
+
+        self.assertTrue(self.sqlur.is_tracking('tbl1'))
+        self.assertTrue(self.sqlur.is_tracking('TBL1'))
+        self.assertFalse(self.sqlur.is_tracking('tbl3'))
+
+        self.sqlur.freeze_table('tbl2')
+
+        self.assertFalse(self.sqlur.is_tracking('tbl2'))
+
+        self.sqlur.deactivate()
+
+        self.assertFalse(self.sqlur.is_tracking('tbl1'))
+
+    def test_reactivate_with(self):
+        self.test_db.execute("CREATE TABLE tbl3(c)")
+        self.sqlur.activate('tbl1', 'tbl2')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (42,))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (69,))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (404,))
+        self.sqlur.barrier()
+        self.sqlur.undo()
+
+        self.sqlur.reactivate_with('tbl1', 'tbl3')
+
+        self.assertEqual(self.sqlur._tables, ['tbl1', 'tbl3'])
+        self.assertEqual(len(self._get_triggers(self.test_db)), 6)
+        self.assertEqual(self.sqlur._undo['undostack'], [[3, 3]])
+        self.assertEqual(self.sqlur._undo['redostack'], [[4, 4]])
+        self.assertEqual(self.test_db.execute("SELECT seq FROM undolog").fetchall(),
+                         [(3,), (4,)])
+
+        self.test_db.execute("INSERT INTO tbl3 VALUES(?)", (500,))
+        self.sqlur.barrier()
+        self.sqlur.undo()
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(23,)])
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(), [])
+
+    def test_reactivate_with_strict(self):
+        self.sqlur.set_strict(True)
+        self.sqlur.activate('tbl1', 'tbl2')
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+
+        self.sqlur.reactivate_with('tbl1')
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[2, 2]])
+
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (69,))
+        self.sqlur.reactivate_with('tbl1')
+
+        with self.assertRaises(UntrackedWriteError):
+            self.sqlur.barrier()
+
+    def test_reactivate_with_unknown_table(self):
+        self.sqlur.activate('tbl1')
+
+        with self.assertRaises(UnknownTableError):
+            self.sqlur.reactivate_with('tbl1', 'does_not_exist')
+
+        self.assertEqual(self.sqlur._tables, ['tbl1'])
+        self.assertEqual(len(self._get_triggers(self.test_db)), 3)
+
+    def test_set_active_tables(self):
+        self.test_db.execute("CREATE TABLE tbl3(c)")
+        self.sqlur.activate('tbl1', 'tbl2')
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (23,))
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur.set_active_tables('tbl1', 'tbl3'),
+                         (['tbl3'], ['tbl2']))
+
+        self.assertEqual(self.sqlur._tables, ['tbl1', 'tbl3'])
+        self.assertEqual(sorted(self._get_triggers(self.test_db)),
+                         [('_tbl1_dt',), ('_tbl1_it',), ('_tbl1_ut',),
+                          ('_tbl3_dt',), ('_tbl3_it',), ('_tbl3_ut',)])
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (42,))
+        self.test_db.execute("INSERT INTO tbl3 VALUES(?)", (69,))
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [2, 2]])
+
+    def test_set_active_tables_while_not_active(self):
+        self.assertEqual(self.sqlur.set_active_tables('tbl1'), (['tbl1'], []))
+        self.assertEqual(self.sqlur._undo['active'], 1)
+
+    def test_event_then_on_idle(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+
+        self.sqlur.event()
+        self.sqlur.event()
+
+        self.assertEqual(self.sqlur._undo['pending'], [1])
+        self.assertEqual(self.sqlur._undo['undostack'], [])
+
+        self.sqlur.on_idle()
+
+        self.assertEqual(self.sqlur._undo['pending'], [])
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+
+    def test_event_without_on_idle(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.event()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+
+        self.assertEqual(self.sqlur._undo['undostack'], [])
+
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['pending'], [])
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 2]])
+
+    def test_on_idle_without_event(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+
+        with mock.patch.object(self.sqlur, 'barrier') as mock_barrier:
+            self.sqlur.on_idle()
+
+        mock_barrier.assert_not_called()
+
+    def test_barrier(self):
+        self.sqlur.activate('tbl1')
+
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [2, 2]])
+
+    def test_barrier_several_changes(self):
+        self.sqlur.activate('tbl1')
+
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 2]])
+
+    def test_barrier_while_not_active(self):
+        self.assertEqual(self.sqlur._undo['active'], 0)
+
+        with mock.patch.object(self.sqlur, '_db') as mock_db:
+            self.sqlur.barrier()
+
+        mock_db.execute.assert_not_called()
+
+        self.sqlur.activate()
+
+        with mock.patch.object(self.sqlur, '_db') as mock_db:
+            mock_db.execute.return_value.fetchone.return_value = (0,)
+            self.sqlur.barrier()
+
+        self.assertEqual(mock_db.execute.call_count, 1)
+
+    def test_barrier_while_frozen(self):
+        self.sqlur.activate('tbl1')
+
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.sqlur.freeze()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+
+    def test_barrier_around_unfreeze(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.sqlur.freeze()
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+        self.sqlur.barrier()
+        self.sqlur.unfreeze()
+
+        self.assertEqual(self.sqlur._undo['firstlog'], 2)
+
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (69,))
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [2, 2]])
+        self.assertEqual(self.sqlur._undo['firstlog'], 3)
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(23,), (42,)])
+
+    def test_barrier_while_frozen_then_undo(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.sqlur.freeze()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (69,))
+        self.sqlur.barrier()
+        self.sqlur.unfreeze()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (404,))
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [2, 2]])
+        q = "SELECT count(*) FROM undolog WHERE seq>=? AND seq<=?"
+        for (begin, end) in self.sqlur._undo['undostack']:
+            self.assertEqual(self.test_db.execute(q, (begin, end)).fetchone()[0],
+                             end - begin + 1)
+
+        self.assertEqual(self.sqlur.undo(), 1)
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(23,), (42,), (69,)])
+
+        self.assertEqual(self.sqlur.undo(), 1)
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(42,), (69,)])
+
+    def test_barrier_after_no_changes(self):
+        self.sqlur.activate('tbl1')
+
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+        self.sqlur.barrier()
+        self.sqlur.undo()
+        with mock.patch.object(self.sqlur, '_db', wraps=self.test_db) as mock_db:
+            self.sqlur.barrier()
+
+        self.assertEqual(mock_db.execute.call_count, 1)
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+        self.assertEqual(self.sqlur._undo['redostack'], [[2, 2]])
+        self.assertEqual(self.sqlur._undo['firstlog'], 3)
+
+    def test_barrier_repeated(self):
+        self.sqlur.activate('tbl1')
+        rng = random.Random(23)
+        depth = 0
+        changed = False
+
+        for i in range(500):
+            action = rng.choice(['insert', 'update', 'noop', 'barrier', 'barrier'])
+            if action == 'insert':
+                self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (i,))
+                changed = True
+            elif action == 'update':
+                changed |= self.test_db.execute(
+                    "UPDATE tbl1 SET a=? WHERE rowid=?", (i, rng.randint(1, 50))
+                ).rowcount > 0
+            elif action == 'noop':
+                self.test_db.execute("DELETE FROM tbl1 WHERE a IS NULL")
+            else:
+                self.sqlur.barrier()
+                depth += changed
+                changed = False
+                self.assertEqual(self.sqlur._undo['firstlog'],
+                                 self.sqlur.last_seq() + 1)
+            self.assertEqual(len(self.sqlur._undo['undostack']), depth)
+
+        undostack = self.sqlur._undo['undostack']
+        for (previous, interval) in zip(undostack, undostack[1:]):
+            self.assertEqual(interval[0], previous[1] + 1)
+
+    def test_barrier_reused_statement(self):
+        self.sqlur.activate('tbl1')
+        cursor = self.test_db.cursor()
+        insert = "INSERT INTO tbl1 VALUES(?)"
+
+        cursor.execute(insert, (23,))
+        self.sqlur.barrier()
+        cursor.execute(insert, (42,))
+        cursor.execute(insert, (69,))
+        self.sqlur.barrier()
+        cursor.execute(insert, (404,))
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [2, 3], [4, 4]])
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(23,), (42,), (69,)])
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(23,)])
+
+    def test_barrier_after_ignored_insert(self):
+        self.test_db.execute("CREATE TABLE tbl3(a UNIQUE)")
+        self.test_db.execute("INSERT INTO tbl3 VALUES(?)", (23,))
+        self.sqlur.activate('tbl3')
+
+        self.test_db.execute("INSERT OR IGNORE INTO tbl3 VALUES(?)", (23,))
+        self.sqlur.barrier()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM undolog").fetchall(), [])
+        self.assertEqual(self.sqlur._undo['undostack'], [])
+
+    def test_transaction(self):
+        self.sqlur.activate('tbl1')
+
+        def edit(db):
+            db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+            db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+            return 'done'
+
+        self.assertEqual(self.sqlur.transaction(edit), 'done')
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 2]])
+
+    def test_transaction_error(self):
+        self.sqlur.activate('tbl1')
+        self.sqlur.set_strict(True)
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+
+        def edit(db):
+            db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+            raise ValueError
+
+        with self.assertRaises(ValueError):
+            self.sqlur.transaction(edit)
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(23,)])
+        self.assertEqual(len(self.test_db.execute("SELECT * FROM undolog").fetchall()), 1)
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+
+    def test_barrier_conflict_clauses(self):
+        statements = ["INSERT OR {} INTO tbl3 VALUES(3, 'z'), (2, 'y')",
+                      "UPDATE OR {} tbl3 SET a=a+2"]
+        for clause in ('IGNORE', 'REPLACE', 'ABORT', 'FAIL', 'ROLLBACK'):
+            for statement in statements:
+                with self.subTest(statement.format(clause)):
+                    self.test_db.execute("DROP TABLE IF EXISTS tbl3")
+                    self.test_db.execute("CREATE TABLE tbl3(a UNIQUE, b)")
+                    self.test_db.executemany("INSERT INTO tbl3 VALUES(?, ?)",
+                                             [(1, 'w'), (2, 'x'), (4, 'v')])
+                    q = "SELECT rowid, * FROM tbl3 ORDER BY rowid"
+                    before = self.test_db.execute(q).fetchall()
+                    sqlur = SQLiteUndoRedo.activated(self.test_db, ['tbl3'],
+                                                     track_replace=True)
+                    try:
+                        self.test_db.execute(statement.format(clause))
+                    except sqlite3.IntegrityError:
+                        self.assertIn(clause, ('ABORT', 'FAIL', 'ROLLBACK'))
+                    after = self.test_db.execute(q).fetchall()
+                    sqlur.barrier()
+
+                    self.assertEqual(len(sqlur._undo['undostack']),
+                                     0 if before == after else 1)
+                    if sqlur._undo['undostack']:
+                        sqlur.undo()
+                        self.assertEqual(self.test_db.execute(q).fetchall(), before)
+                        sqlur.redo()
+                        self.assertEqual(self.test_db.execute(q).fetchall(), after)
+                    sqlur.deactivate()
+
+    def test_barrier_conflict_rollback_in_transaction(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("CREATE UNIQUE INDEX tbl1_a ON tbl1(a)")
+        self.test_db.execute("BEGIN")
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+
+        with self.assertRaises(sqlite3.IntegrityError):
+            self.test_db.execute("INSERT OR ROLLBACK INTO tbl1 VALUES(?)", (23,))
+
+        self.assertFalse(self.test_db.in_transaction)
+        self.assertFalse(self.sqlur.verify())
+
+        self.sqlur.reset()
+
+        self.assertTrue(self.sqlur.verify())
+
+    def test_barrier_labeled(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier_labeled('insert 23')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+
+        with mock.patch('sqlite_undoredo.time.time', return_value=1000.0):
+            self.sqlur.barrier()
+
+        steps = list(self.sqlur.undo_steps())
+
+        self.assertEqual(steps, [[1, 1], [2, 2]])
+        self.assertEqual([step.label for step in steps], ['insert 23', None])
+        self.assertIsInstance(steps[0].at, float)
+        self.assertEqual(steps[1].at, 1000.0)
+        self.assertEqual((steps[1].begin, steps[1].end), (2, 2))
+        self.assertEqual(list(self.sqlur.undo_intervals()), [(1, 1), (2, 2)])
+
+        self.sqlur.undo()
+        self.sqlur.undo()
+
+        steps = list(self.sqlur.redo_steps())
+
+        self.assertEqual([step.label for step in steps], [None, 'insert 23'])
+        self.assertEqual(steps[0].at, 1000.0)
+
+        self.sqlur.redo()
+
+        self.assertEqual([step.label for step in self.sqlur.undo_steps()],
+                         ['insert 23'])
+
+    def test_barrier_keep_redo(self):
+        self.sqlur.activate('tbl1', 'tbl2')
+        discarded = []
+        self.sqlur.on_redo_discarded(discarded.append)
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+        self.sqlur.barrier()
+        self.sqlur.undo()
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (69,))
+
+        self.sqlur.barrier_keep_redo()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [3, 3]])
+        self.assertEqual(self.sqlur._undo['redostack'], [[2, 2]])
+        self.assertEqual(discarded, [])
+
+        self.sqlur.redo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(23,), (42,)])
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [3, 3], [4, 4]])
+
+        self.sqlur.undo()
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(23,)])
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl2").fetchall(), [])
+
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (404,))
+        self.sqlur.barrier()
+
+        self.assertEqual(discarded, [2])
+
+    def test_group(self):
+        self.sqlur.activate('tbl1')
+
+        with self.sqlur.group() as sqlur:
+            self.assertIs(sqlur, self.sqlur)
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 2]])
+
+    def test_group_exception(self):
+        self.sqlur.activate('tbl1')
+
+        with self.assertRaises(ZeroDivisionError):
+            with self.sqlur.group():
+                self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+                1 / 0
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+
+    def test_undo(self):
+        with mock.patch.object(self.sqlur, '_step') as mock_step:
+            result = self.sqlur.undo()
+
+        mock_step.assert_called_with('undostack', 'redostack')
+        self.assertIs(result, mock_step.return_value)
+
+    def test_undo_one_statement(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur.undo_one_statement(), 1)
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+        self.assertEqual(self.sqlur._undo['redostack'], [[2, 2]])
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(23,)])
+
+        self.assertEqual(self.sqlur.undo_one_statement(), 1)
+
+        self.assertEqual(self.sqlur._undo['undostack'], [])
+        self.assertEqual(self.sqlur._undo['redostack'], [[2, 2], [3, 3]])
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [])
+
+        self.sqlur.redo()
+        self.sqlur.redo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(23,), (42,)])
+
+    def test_undo_n(self):
+        self.sqlur.activate('tbl1')
+        for value in (23, 42, 69):
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (value,))
+            self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur.undo_n(2), 2)
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(23,)])
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+        self.assertEqual(len(self.sqlur._undo['redostack']), 2)
+
+    def test_undo_n_too_many(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+
+        with self.assertRaises(UndoError):
+            self.sqlur.undo_n(2)
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+
+    def test_undo_cost(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
+        self.sqlur.barrier()
+        self.test_db.execute("DELETE FROM tbl1")
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (69,))
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur.undo_cost(0), 0)
+        self.assertEqual(self.sqlur.undo_cost(1), 1)
+        self.assertEqual(self.sqlur.undo_cost(3), 5)
+        self.assertEqual(self.sqlur.undo_cost(3), self.sqlur.undo_n(3))
+
+        with self.assertRaises(UndoError):
+            self.sqlur.undo_cost(1)
+
+    def test_undo_to_redo_to(self):
+        self.sqlur.activate('tbl1')
+        for value in (23, 42, 69):
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (value,))
+            self.sqlur.barrier_labeled(f"insert {value}")
+
+        self.assertEqual(self.sqlur.undo_to('insert 23'), 2)
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(23,)])
+        self.assertEqual(len(self.sqlur._undo['redostack']), 2)
+
+        self.assertEqual(self.sqlur.undo_to('insert 23'), 0)
+        self.assertEqual(self.sqlur.redo_to('insert 69'), 2)
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(23,), (42,), (69,)])
+        self.assertEqual(self.sqlur._undo['redostack'], [])
+
+        self.sqlur.undo_to('insert 23')
+        self.sqlur.redo_to('insert 42')
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(23,), (42,)])
+        self.assertEqual([step.label for step in self.sqlur.undo_steps()],
+                         ['insert 23', 'insert 42'])
+
+    def test_undo_to_redo_to_unknown_label(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier_labeled('insert 23')
+
+        with self.assertRaises(UndoError):
+            self.sqlur.redo_to('insert 23')
+        with self.assertRaises(UndoError):
+            self.sqlur.undo_to('insert 42')
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+
+    def test_redo_user_trigger(self):
+        self.test_db.execute("CREATE TRIGGER copy AFTER INSERT ON tbl1"
+                             " BEGIN INSERT INTO tbl2 VALUES(new.a); END")
+        self.sqlur.activate('tbl1', 'tbl2')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.sqlur.undo()
+
+        with self.assertRaises(sqlite3.IntegrityError):
+            self.sqlur.redo()
+
+        self.assertFalse(self.test_db.in_transaction)
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [])
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl2").fetchall(), [])
+        self.assertEqual(self.sqlur._undo['undostack'], [])
+        self.assertEqual(self.sqlur._undo['redostack'], [[1, 2]])
+        self.assertEqual(self.sqlur._undo['firstlog'], 3)
+
+        self.sqlur.set_replay_user_triggers(False)
+        self.sqlur.redo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(23,)])
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl2").fetchall(), [(23,)])
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 2]])
+
+        self.sqlur.undo()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl2").fetchall(), [(42,)])
+
+    def test_undo_n_error(self):
+        self.sqlur.activate('tbl1')
+        for value in (23, 42, 69):
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (value,))
+            self.sqlur.barrier()
+
+        def callback(sql):
+            if sql.endswith("rowid=2"):
+                raise ValueError
+
+        self.sqlur.on_replay(callback)
+
+        with self.assertRaises(ValueError):
+            self.sqlur.undo_n(3)
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(23,), (42,), (69,)])
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [2, 2], [3, 3]])
+        self.assertEqual(self.sqlur._undo['redostack'], [])
+        self.assertEqual(self.sqlur._undo['firstlog'], 4)
+
+    def test_merge_last(self):
+        self.sqlur.activate('tbl1')
+        for value in (23, 42, 69):
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (value,))
+            self.sqlur.barrier()
+
+        self.sqlur.merge_last(2)
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [2, 3]])
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(23,)])
+
+    def test_merge_last_after_undo(self):
+        self.sqlur.activate('tbl1')
+        for value in (23, 42):
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (value,))
+            self.sqlur.barrier()
+        self.sqlur.undo()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (69,))
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [2, 2]])
+
+        self.sqlur.merge_last(2)
+
+        self.assertTrue(self.sqlur.verify())
+        self.assertEqual(self.sqlur.undo_cost(1), 2)
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [])
+
+    def test_merge_last_kept_redo(self):
+        self.sqlur.activate('tbl1')
+        for value in (23, 42):
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (value,))
+            self.sqlur.barrier()
+        self.sqlur.undo()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (69,))
+        self.sqlur.barrier_keep_redo()
+
+        self.sqlur.merge_last(2)
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[2, 3]])
+        self.assertEqual(self.sqlur._undo['redostack'], [[1, 1]])
+        self.assertTrue(self.sqlur.verify())
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [])
+
+        self.sqlur.redo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(23,), (69,)])
+
+    def test_merge_last_after_redo(self):
+        self.sqlur.activate('tbl1')
+        for value in (23, 42, 69):
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (value,))
+            self.sqlur.barrier()
+        self.sqlur.undo()
+        self.sqlur.undo()
+        self.sqlur.redo()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [4, 4]])
+        self.assertEqual(self.sqlur._undo['redostack'], [[3, 3]])
+
+        self.sqlur.merge_last(2)
+
+        self.assertTrue(self.sqlur.verify())
+        self.assertEqual(self.sqlur.undo_cost(1), 2)
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [])
+
+        self.sqlur.redo()
+        self.sqlur.redo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(23,), (42,), (69,)])
+
+    def test_merge_last_after_redos(self):
+        self.sqlur.activate('tbl1')
+        for value in (23, 42, 69):
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (value,))
+            self.sqlur.barrier()
+        self.sqlur.undo()
+        self.sqlur.undo()
+        self.sqlur.redo()
+        self.sqlur.redo()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (404,))
+
+        self.sqlur.merge_last(3)
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 3]])
+        self.assertTrue(self.sqlur.verify())
+        self.assertEqual(self.sqlur.undo_cost(1), 3)
+
+        self.sqlur.barrier()
+        self.sqlur.undo()
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [])
+
+    def test_merge_last_too_many(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+
+        with self.assertRaises(UndoError):
+            self.sqlur.merge_last(2)
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+
+    def test_rollback_to_checkpoint(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        checkpoint = self.sqlur.checkpoint()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+        self.sqlur.barrier()
+        self.test_db.execute("UPDATE tbl1 SET a=?", (69,))
+
+        self.sqlur.rollback_to_checkpoint(checkpoint)
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(23,)])
+        self.assertEqual(len(self.sqlur._undo['undostack']), 1)
+        self.assertEqual(len(self.sqlur._undo['redostack']), 2)
+
+    def test_rollback_to_checkpoint_unreachable(self):
+        self.sqlur.activate('tbl1')
+        self.sqlur.set_max_total_rows(1)
+        checkpoint = self.sqlur.checkpoint()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+        self.sqlur.barrier()
+
+        with self.assertRaises(UndoError):
+            self.sqlur.rollback_to_checkpoint(checkpoint)
+
+        checkpoint = self.sqlur.checkpoint()
+        self.sqlur.undo()
+
+        with self.assertRaises(UndoError):
+            self.sqlur.rollback_to_checkpoint(checkpoint)
+
+        checkpoint = self.sqlur.checkpoint()
+        self.sqlur.reset()
+
+        with self.assertRaises(UndoError):
+            self.sqlur.rollback_to_checkpoint(checkpoint)
+
+    def test_rollback_to_checkpoint_replaced(self):
+        self.sqlur.activate('tbl1')
+        for value in (1, 2):
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (value,))
+            self.sqlur.barrier()
+        checkpoint = self.sqlur.checkpoint()
+        self.sqlur.undo()
+        self.sqlur.redo()
+        self.sqlur.undo()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (99,))
+        self.sqlur.barrier()
+
+        with self.assertRaises(UndoError):
+            self.sqlur.rollback_to_checkpoint(checkpoint)
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(1,), (99,)])
+
+    def test_rollback_to_checkpoint_after_redo(self):
+        self.sqlur.activate('tbl1')
+        for value in (1, 2):
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (value,))
+            self.sqlur.barrier()
+        checkpoint = self.sqlur.checkpoint()
+        self.sqlur.undo()
+        self.sqlur.redo()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (99,))
+        self.sqlur.barrier()
+
+        self.sqlur.rollback_to_checkpoint(checkpoint)
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(1,), (2,)])
+
+    def test_rollback_to_checkpoint_error(self):
+        self.sqlur.activate('tbl1')
+        checkpoint = self.sqlur.checkpoint()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+        self.sqlur.barrier()
+
+        def callback(sql):
+            if sql.endswith("rowid=1"):
+                raise ValueError
+
+        self.sqlur.on_replay(callback)
+
+        with self.assertRaises(ValueError):
+            self.sqlur.rollback_to_checkpoint(checkpoint)
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(23,), (42,)])
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [2, 2]])
+        self.assertEqual(self.sqlur._undo['redostack'], [])
+
+    def test_redo(self):
+        with mock.patch.object(self.sqlur, '_step') as mock_step:
+            result = self.sqlur.redo()
+
+        mock_step.assert_called_with('redostack', 'undostack')
+        self.assertIs(result, mock_step.return_value)
+
+    def test_export_changelog(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+
+        self.assertEqual(
+            self.sqlur.export_changelog(),
+            '{"seq": 1, "sql": "DELETE FROM tbl1 WHERE rowid=1"}\n',
+        )
+
+    def test_apply_changelog(self):
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("UPDATE tbl1 SET a=?", (42,))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (69,))
+        self.sqlur.barrier()
+        changelog = self.sqlur.export_changelog()
+
+        other_db = sqlite3.connect(':memory:')
+        other_db.isolation_level = None
+        other_db.execute("CREATE TABLE tbl1(a)")
+        other_db.executemany("INSERT INTO tbl1 VALUES(?)", [(42,), (69,)])
+
+        self.assertEqual(apply_changelog(other_db, changelog), 2)
+
+        self.assertEqual(other_db.execute("SELECT rowid, a FROM tbl1").fetchall(),
+                         [(1, 23)])
+        other_db.close()
+
+    def test_apply_changelog_error(self):
+        self.sqlur.activate('tbl1', 'tbl2')
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (42,))
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        changelog = self.sqlur.export_changelog()
+
+        other_db = sqlite3.connect(':memory:')
+        other_db.isolation_level = None
+        other_db.execute("CREATE TABLE tbl1(a)")
+        other_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+
+        with self.assertRaises(sqlite3.OperationalError):
+            apply_changelog(other_db, changelog)
+
+        self.assertFalse(other_db.in_transaction)
+        self.assertEqual(other_db.execute("SELECT * FROM tbl1").fetchall(), [(23,)])
+        other_db.close()
+
+    def test_save_state(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+        self.sqlur.barrier()
+        self.sqlur.undo()
+
+        self.assertEqual(
+            self.sqlur.save_state(),
+            SavedUndoState(
+                undostack=[[1, 1]],
+                redostack=[[2, 2]],
+                firstlog=3,
+                undolog=[[1, 'DELETE FROM tbl1 WHERE rowid=1'],
+                         [2, 'INSERT INTO tbl1(rowid,"a") VALUES(2,42)']],
+            ),
+        )
+
+    def test_restore_state(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("UPDATE tbl1 SET a=?", (42,))
+        self.sqlur.barrier()
+        saved = json.dumps(self.sqlur.save_state()._asdict())
+
+        self.sqlur.deactivate()
+        self.sqlur.activate('tbl1')
+        self.sqlur.restore_state(SavedUndoState(**json.loads(saved)))
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [2, 2]])
+        self.assertEqual(self.sqlur._undo['firstlog'], 3)
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(23,)])
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [])
+
+    def test_restore_state_error(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        state = SavedUndoState([[1, 2]], [], 3, [[1, 'DELETE FROM tbl1 WHERE rowid=1'],
+                                                 [1, 'DELETE FROM tbl1 WHERE rowid=2']])
+
+        with self.assertRaises(sqlite3.IntegrityError):
+            self.sqlur.restore_state(state)
+
+        self.assertFalse(self.test_db.in_transaction)
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+        self.assertEqual(self.test_db.execute("SELECT seq FROM undolog").fetchall(),
+                         [(1,)])
+
+    def test_restore_state_while_not_active(self):
+        with self.assertRaises(UndoError):
+            self.sqlur.restore_state(SavedUndoState([], [], 1, []))
+
+    def test_export_json(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.sqlur.freeze()
+
+        self.assertEqual(json.loads(self.sqlur.export_json()), {
+            'undostack': [[1, 1]],
+            'redostack': [],
+            'firstlog': 2,
+            'undolog': [[1, 'DELETE FROM tbl1 WHERE rowid=1']],
+            'freeze': 1,
+        })
+
+    def test_import_json(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("UPDATE tbl1 SET a=?", (42,))
+        self.sqlur.barrier()
+        self.sqlur.undo()
+        text = self.sqlur.export_json()
+
+        self.sqlur.deactivate()
+        self.sqlur.activate('tbl1')
+        self.sqlur.import_json(text)
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+        self.assertEqual(self.sqlur._undo['redostack'], [[2, 2]])
+        self.assertIsNone(self.sqlur.freeze_point())
+
+        self.sqlur.redo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(42,)])
+
+    def test_boundary_state(self):
+        self.sqlur.activate('tbl1')
+
+        self.assertEqual(self.sqlur.boundary_state(),
+                         BoundaryState(at_newest=True, at_oldest=True))
+
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur.boundary_state(),
+                         BoundaryState(at_newest=True, at_oldest=False))
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.sqlur.boundary_state(),
+                         BoundaryState(at_newest=False, at_oldest=False))
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.sqlur.boundary_state(),
+                         BoundaryState(at_newest=False, at_oldest=True))
+
+        self.sqlur.redo()
+        self.sqlur.redo()
+
+        self.assertEqual(self.sqlur.boundary_state(),
+                         BoundaryState(at_newest=True, at_oldest=False))
+
+    def test_conn(self):
+        self.assertIs(self.sqlur.conn(), self.test_db)
+
+    def test_depths(self):
+        self.assertEqual(self.sqlur.depths(), (0, 0))
+
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+        self.sqlur.barrier()
+        self.sqlur.undo()
+
+        self.assertEqual(self.sqlur.depths(), (1, 1))
+
+        self.sqlur.deactivate()
+
+        self.assertEqual(self.sqlur.depths(), (0, 0))
+
+    def test_undo_intervals(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (69,))
+        self.sqlur.barrier()
+
+        self.assertEqual(list(self.sqlur.undo_intervals()), [(1, 2), (3, 3)])
+        self.assertEqual(list(self.sqlur.redo_intervals()), [])
+
+    def test_redo_intervals(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
+        self.sqlur.barrier()
+        self.sqlur.undo()
+
+        self.assertEqual(list(self.sqlur.undo_intervals()), [])
+        self.assertEqual(list(self.sqlur.redo_intervals()), [(1, 2)])
+
+    def test_preview_undo(self):
+        self.assertEqual(self.sqlur.preview_undo(), [])
+
+        self.sqlur.activate('tbl1')
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur.preview_undo(),
+                         ["DELETE FROM tbl1 WHERE rowid=2",
+                          "DELETE FROM tbl1 WHERE rowid=1"])
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 2]])
+
+    def test_preview_undo_iter(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)",
+                                 [(value,) for value in range(1000)])
+        self.sqlur.barrier()
+
+        statements = self.sqlur.preview_undo_iter()
+
+        self.assertEqual(next(statements), "DELETE FROM tbl1 WHERE rowid=1000")
+        self.assertEqual(sum(1 for sql in statements), 999)
+
+    def test_dump_history(self):
+        self.assertEqual(self.sqlur.dump_history(), [])
+
+        self.sqlur.activate('tbl1')
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
+        self.sqlur.barrier()
+        self.test_db.execute("UPDATE tbl1 SET a=? WHERE a=?", (69, 42))
+        self.sqlur.barrier()
+        self.test_db.execute("DELETE FROM tbl1")
+        self.sqlur.barrier()
+        self.sqlur.undo()
+
+        self.assertEqual(
+            self.sqlur.dump_history(),
+            [(1, 2, ["DELETE FROM tbl1 WHERE rowid=2", "DELETE FROM tbl1 WHERE rowid=1"]),
+             (3, 3, ['UPDATE tbl1 SET "a"=42 WHERE rowid=2'])])
+
+    def test_structured_undo(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)",
+                                 [(23,), ("a, 'b' WHERE c",)])
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur.structured_undo(), [
+            ChangeRecord(table='tbl1', op='DELETE', rowid=2, columns={}),
+            ChangeRecord(table='tbl1', op='DELETE', rowid=1, columns={}),
+        ])
+
+        self.test_db.execute("UPDATE tbl1 SET a=? WHERE rowid=2", (b'\x01',))
+        self.test_db.execute("DELETE FROM tbl1 WHERE rowid=1")
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur.structured_undo(), [
+            ChangeRecord(table='tbl1', op='INSERT', rowid=1, columns={'a': 23}),
+            ChangeRecord(table='tbl1', op='UPDATE', rowid=2,
+                         columns={'a': "a, 'b' WHERE c"}),
+        ])
+
+    def test_structured_undo_without_rowid(self):
+        self.test_db.execute("CREATE TABLE tbl3(k PRIMARY KEY, v) WITHOUT ROWID")
+        self.sqlur.activate('tbl3')
+        self.test_db.execute("INSERT INTO tbl3 VALUES(?, ?)", ('x,y', 1.5))
+        self.test_db.execute("UPDATE tbl3 SET v=NULL")
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur.structured_undo(), [
+            ChangeRecord(table='tbl3', op='UPDATE', rowid=None,
+                         columns={'k': 'x,y', 'v': 1.5}),
+            ChangeRecord(table='tbl3', op='DELETE', rowid=None, columns={'k': 'x,y'}),
+        ])
+
+    def test_structured_undo_empty(self):
+        self.sqlur.activate('tbl1')
+
+        self.assertEqual(self.sqlur.structured_undo(), [])
+
+    def test_peek_undo(self):
+        self.assertIsNone(self.sqlur.peek_undo())
+
+        self.sqlur.activate('tbl1')
+
+        self.assertIsNone(self.sqlur.peek_undo())
+
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (69,))
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur.peek_undo(), (3, 3))
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 2], [3, 3]])
+
+    def test_peek_redo(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
+        self.sqlur.barrier()
+
+        self.assertIsNone(self.sqlur.peek_redo())
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.sqlur.peek_redo(), (1, 2))
+        self.assertEqual(self.sqlur.peek_undo(), None)
+
+    def test_undo_label_redo_label(self):
+        self.assertIsNone(self.sqlur.undo_label())
+
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier_labeled('Typing')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+        self.sqlur.barrier()
+
+        self.assertIsNone(self.sqlur.undo_label())
+        self.assertIsNone(self.sqlur.redo_label())
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.sqlur.undo_label(), 'Typing')
+        self.assertIsNone(self.sqlur.redo_label())
+
+        self.sqlur.undo()
+
+        self.assertIsNone(self.sqlur.undo_label())
+        self.assertEqual(self.sqlur.redo_label(), 'Typing')
+
+    def test_would_undo_change_rows(self):
+        self.sqlur.activate('tbl1')
+
+        self.assertEqual(self.sqlur.would_undo_change_rows(), 0)
+
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
+        self.sqlur.barrier()
+        log = self.test_db.execute("SELECT * FROM undolog").fetchall()
+
+        self.assertEqual(self.sqlur.would_undo_change_rows(), 2)
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM undolog").fetchall(), log)
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(23,), (42,)])
+
+        self.test_db.execute("UPDATE tbl1 SET a=a")
+        self.sqlur.barrier()
+        self.test_db.execute("DELETE FROM tbl1")
+
+        self.assertEqual(self.sqlur.would_undo_change_rows(), 0)
+
+    def test_would_undo_change_rows_strict(self):
+        self.sqlur.set_strict(True)
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+
+        self.assertEqual(self.sqlur.would_undo_change_rows(), 1)
+
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [2, 2]])
+
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (69,))
+        self.sqlur.would_undo_change_rows()
+
+        with self.assertRaises(UntrackedWriteError):
+            self.sqlur.barrier()
+
+    def test_last_step_changes(self):
+        self.assertEqual(self.sqlur.last_step_changes(), 0)
+
+        self.sqlur.activate('tbl1')
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (69,))
+        self.sqlur.barrier()
+
+        self.sqlur.undo_n(2)
+
+        self.assertEqual(self.sqlur.last_step_changes(), 3)
+
+        self.sqlur.redo()
+
+        self.assertEqual(self.sqlur.last_step_changes(), 2)
+
+        self.sqlur.freeze()
+        self.test_db.execute("DELETE FROM tbl1")
+        self.sqlur.unfreeze()
+        self.sqlur.undo()
+
+        self.assertEqual(self.sqlur.last_step_changes(), 0)
+
+    def test_first_log(self):
+        self.assertEqual(self.sqlur.first_log(), 1)
+
+        self.sqlur.activate('tbl1')
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
+
+        self.assertEqual(self.sqlur.first_log(), 1)
+
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur.first_log(), 3)
+
+    def test_last_seq(self):
+        self.assertEqual(self.sqlur.last_seq(), 0)
+
+        self.sqlur.activate('tbl1')
+
+        self.assertEqual(self.sqlur.last_seq(), 0)
+
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
+
+        self.assertEqual(self.sqlur.last_seq(), 2)
+
+    def test_has_pending_changes(self):
+        self.assertIs(self.sqlur.has_pending_changes(), False)
+
+        self.sqlur.activate('tbl1')
+
+        self.assertFalse(self.sqlur.has_pending_changes())
+
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+
+        self.assertTrue(self.sqlur.has_pending_changes())
+
+        self.sqlur.barrier()
+
+        self.assertFalse(self.sqlur.has_pending_changes())
+
+        with self.sqlur.frozen():
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+
+            self.assertFalse(self.sqlur.has_pending_changes())
+
+    def test_verify(self):
+        self.assertTrue(self.sqlur.verify())
+
+        self.sqlur.activate('tbl1', 'tbl2')
+        for value in (23, 42, 69):
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (value,))
+            self.sqlur.barrier()
+        self.sqlur.undo()
+        self.sqlur.freeze()
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (0,))
+        self.sqlur.barrier()
+        self.sqlur.unfreeze()
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (404,))
+        self.sqlur.barrier_keep_redo()
+
+        self.assertTrue(self.sqlur.verify())
+
+        self.sqlur.undo()
+        self.sqlur.redo()
+        self.sqlur.redo()
+
+        self.assertTrue(self.sqlur.verify())
+
+    def test_verify_missing_rows(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
+        self.sqlur.barrier()
+        self.test_db.execute("DELETE FROM undolog WHERE seq=2")
+
+        self.assertFalse(self.sqlur.verify())
+
+    def test_verify_overlapping_intervals(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
+        self.sqlur.barrier()
+        self.sqlur._undo['undostack'].append([2, 2])
+
+        self.assertFalse(self.sqlur.verify())
+
+    def test_verify_pending_overlap(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.sqlur._undo['firstlog'] = 1
+
+        self.assertFalse(self.sqlur.verify())
+
+    def test_log_row_count(self):
+        self.assertEqual(self.sqlur.log_row_count(), 0)
+
+        self.sqlur.activate('tbl1')
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
+
+        self.assertEqual(self.sqlur.log_row_count(), 2)
+
+    def test_log_size_bytes(self):
+        self.assertEqual(self.sqlur.log_size_bytes(), 0)
+
+        self.sqlur.activate('tbl1')
+
+        self.assertEqual(self.sqlur.log_size_bytes(), 0)
+
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
+
+        self.assertEqual(self.sqlur.log_size_bytes(),
+                         2 * len("DELETE FROM tbl1 WHERE rowid=1"))
+
+    def test_undo_affected_tables(self):
+        self.sqlur.activate('tbl1', 'tbl2')
+
+        self.assertEqual(self.sqlur.undo_affected_tables(), [])
+
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (42,))
+        self.test_db.execute("UPDATE tbl1 SET a=?", (69,))
+        self.test_db.execute("DELETE FROM tbl2")
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur.undo_affected_tables(), ['tbl2', 'tbl1'])
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.sqlur.undo_affected_tables(), ['tbl1'])
+
+    def test_set_max_total_rows(self):
+        self.sqlur.activate('tbl1')
+        self.sqlur.set_max_total_rows(3)
+
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
+        self.sqlur.barrier()
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(69,), (404,)])
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[3, 4]])
+        self.assertEqual(self.test_db.execute("SELECT seq FROM undolog").fetchall(),
+                         [(3,), (4,)])
+        self.assertEqual(self.sqlur._undo['firstlog'], 5)
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(23,), (42,)])
+        self.assertEqual(self.sqlur._undo['undostack'], [])
+
+    def test_set_max_total_rows_evicts_everything(self):
+        self.sqlur.activate('tbl1')
+        self.sqlur.set_max_total_rows(1)
+
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [])
+        self.assertEqual(self.sqlur._undo['firstlog'], 1)
+
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (69,))
+        self.sqlur.barrier()
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(23,), (42,)])
+
+    def test_set_progress_callback(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)",
+                                 [(i,) for i in range(2500)])
+        self.sqlur.barrier()
+        progress = mock.Mock()
+
+        self.sqlur.set_progress_callback(progress)
+        self.sqlur.undo()
+
+        self.assertEqual(progress.call_args_list,
+                         [mock.call(1000, 2500), mock.call(2000, 2500),
+                          mock.call(2500, 2500)])
+
+        progress.reset_mock()
+        self.sqlur.set_progress_callback(progress, every=1)
+        self.test_db.executemany("INSERT INTO tbl1 VALUES(?)", [(23,), (42,)])
+        self.sqlur.barrier()
+        self.sqlur.undo()
+
+        self.assertEqual(progress.call_args_list, [mock.call(1, 2), mock.call(2, 2)])
+
+    def test_on_replay(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("UPDATE tbl1 SET a=?", (42,))
+        self.sqlur.barrier()
+        replayed = []
+
+        def callback(sql):
+            replayed.append((sql, self.test_db.in_transaction))
+            return False
+
+        self.sqlur.on_replay(callback)
+        self.sqlur.undo()
+
+        self.assertEqual(replayed, [('UPDATE tbl1 SET "a"=23 WHERE rowid=1', True)])
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(23,)])
+
+    def test_on_redo_discarded(self):
+        self.sqlur.activate('tbl1')
+        discarded = []
+        self.sqlur.on_redo_discarded(discarded.append)
+        for value in (23, 42, 69):
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (value,))
+            self.sqlur.barrier()
+
+        self.assertEqual(discarded, [])
+
+        self.sqlur.undo()
+        self.sqlur.undo()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (404,))
+        self.sqlur.barrier()
+
+        self.assertEqual(discarded, [2])
+
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (500,))
+        self.sqlur.barrier()
+
+        self.assertEqual(discarded, [2])
+
+    def test_set_coalesce_window(self):
+        self.sqlur.activate('tbl1')
+        self.sqlur.set_coalesce_window(0.5)
+
+        with mock.patch('sqlite_undoredo.time.monotonic') as mock_monotonic:
+            for (now, value) in [(10.0, 23), (10.3, 42), (10.6, 69), (11.2, 404)]:
+                mock_monotonic.return_value = now
+                self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (value,))
+                self.sqlur.barrier()
+
+            self.assertEqual(self.sqlur._undo['undostack'], [[1, 3], [4, 4]])
+
+            self.sqlur.undo()
+            mock_monotonic.return_value = 11.3
+            self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (500,))
+            self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 3], [4, 4]])
+
+    def test_set_coalesce_window_none(self):
+        self.sqlur.activate('tbl1')
+
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [2, 2]])
+
+    def test_set_strict(self):
+        self.sqlur.set_strict(True)
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.sqlur.undo()
+        self.sqlur.redo()
+        self.test_db.execute("UPDATE tbl1 SET a=?", (42,))
+
+        self.sqlur.barrier()
+
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (69,))
+        self.test_db.execute("DELETE FROM tbl1")
+
+        with self.assertRaises(UntrackedWriteError):
+            self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [2, 2], [3, 3]])
+
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (404,))
+
+        with self.assertRaises(UntrackedWriteError):
+            self.sqlur.barrier()
+
+        self.sqlur.barrier()
+
+    def test_set_strict_replace(self):
+        self.test_db.execute("CREATE TABLE tbl3(k PRIMARY KEY, v)")
+        self.sqlur = SQLiteUndoRedo(self.test_db, track_replace=True)
+        self.sqlur.set_strict(True)
+        self.sqlur.activate('tbl3')
+        self.test_db.execute("INSERT INTO tbl3 VALUES(?, ?)", (1, 'x'))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT OR REPLACE INTO tbl3 VALUES(?, ?)", (1, 'y'))
+
+        self.sqlur.barrier()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1], [2, 3]])
+
+    def test_set_strict_track_autoincrement(self):
+        self.test_db.execute("CREATE TABLE tbl3(id INTEGER PRIMARY KEY AUTOINCREMENT, v)")
+        sqlur = SQLiteUndoRedo(self.test_db, track_autoincrement=True)
+        sqlur.set_strict(True)
+        sqlur.activate('tbl3')
+        self.test_db.executemany("INSERT INTO tbl3(v) VALUES(?)", [('x',), ('y',)])
+
+        sqlur.barrier()
+
+        self.test_db.execute("INSERT INTO tbl3(v) VALUES(?)", ('z',))
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (23,))
+
+        with self.assertRaises(UntrackedWriteError):
+            sqlur.barrier()
+
+    def test_set_strict_skip_noop_updates(self):
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        sqlur = SQLiteUndoRedo(self.test_db, skip_noop_updates=True)
+        sqlur.set_strict(True)
+        sqlur.activate('tbl1')
+        self.test_db.execute("UPDATE tbl1 SET a=a")
+
+        sqlur.barrier()
+
+        self.test_db.execute("UPDATE tbl1 SET a=?", (42,))
+        sqlur.barrier()
+
+        self.assertEqual(sqlur._undo['undostack'], [[1, 1]])
+
+        self.test_db.execute("UPDATE tbl1 SET a=a")
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (23,))
+
+        with self.assertRaises(UntrackedWriteError):
+            sqlur.barrier()
+
+    def test_set_strict_step_error(self):
+        self.sqlur.set_strict(True)
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+        self.sqlur.barrier()
+        self.test_db.execute("UPDATE undolog SET sql='DELETE FROM nowhere' WHERE seq=1")
+        self.sqlur.undo()
+
+        with self.assertRaises(sqlite3.OperationalError):
+            self.sqlur.undo()
+
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (69,))
+        self.sqlur.barrier()
+
+    def test_set_strict_off(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (23,))
+
+        self.sqlur.barrier()
+
+    def test_exclude_column(self):
+        self.test_db.execute("CREATE TABLE tbl3(a, c)")
+        self.sqlur.exclude_column('tbl3', 'c')
+        self.sqlur.activate('tbl3')
+        self.test_db.execute("INSERT INTO tbl3 VALUES(?, ?)", (23, 'x'))
+        self.sqlur.barrier()
+        self.test_db.execute("UPDATE tbl3 SET a=?, c=?", (42, 'y'))
+        self.sqlur.barrier()
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(23, 'y')])
+
+    def test_exclude_column_not_null_without_default(self):
+        self.test_db.execute("CREATE TABLE tbl3(a, c NOT NULL)")
+        self.sqlur.exclude_column('tbl3', 'c')
+
+        with self.assertRaises(UndoError):
+            self.sqlur.activate('tbl3')
+
+        self.assertEqual(self.sqlur._undo['active'], 0)
+        self.assertEqual(self._get_triggers(self.test_db), [])
+
+    def test_exclude_column_not_null_with_placeholder(self):
+        self.test_db.execute("CREATE TABLE tbl3(a, c NOT NULL)")
+        self.sqlur.exclude_column('tbl3', 'c', placeholder="it's")
+        self.sqlur.activate('tbl3')
+        self.test_db.execute("INSERT INTO tbl3 VALUES(?, ?)", (23, 'x'))
+        self.sqlur.barrier()
+        self.test_db.execute("DELETE FROM tbl3")
+        self.sqlur.barrier()
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(23, "it's")])
 
     def test___init__(self):
         self.assertIs(self.sqlur._db, self.test_db)
@@ -262,35 +2346,520 @@ class SQLiteUndoRedoTest(unittest.TestCase):
             },
         )
 
-    def _get_triggers(self, db):
-        return db.execute(
-            "SELECT name FROM sqlite_temp_master WHERE type='trigger'").fetchall()
+    def test_activated(self):
+        sqlur = SQLiteUndoRedo.activated(self.test_db, ['tbl1'], trigger_prefix='app')
+
+        self.assertEqual(sqlur._undo['active'], 1)
+        self.assertEqual(sqlur._tables, ['tbl1'])
+        self.assertEqual(self._get_triggers(self.test_db),
+                         [('app_tbl1_it',), ('app_tbl1_ut',), ('app_tbl1_dt',)])
+
+    def test_log_table(self):
+        doc1 = SQLiteUndoRedo.activated(self.test_db, ['tbl1'],
+                                        log_table='doc1log', trigger_prefix='doc1')
+        doc2 = SQLiteUndoRedo.activated(self.test_db, ['tbl2'],
+                                        log_table='doc2log', trigger_prefix='doc2')
+
+        self.test_db.execute("INSERT INTO tbl1 VALUES(1)")
+        doc1.barrier()
+        self.test_db.execute("INSERT INTO tbl2 VALUES(2)")
+        doc2.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(3)")
+        doc1.barrier()
+
+        self.assertEqual(doc1.depths(), (2, 0))
+        self.assertEqual(doc2.depths(), (1, 0))
+
+        doc2.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(1,), (3,)])
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl2").fetchall(), [])
+        self.assertEqual(doc1.depths(), (2, 0))
+        self.assertEqual(doc2.depths(), (0, 1))
+
+        doc1.deactivate()
+
+        self.assertEqual(self._get_triggers(self.test_db),
+                         [('doc2_tbl2_it',), ('doc2_tbl2_ut',), ('doc2_tbl2_dt',)])
+        doc2.redo()
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl2").fetchall(), [(2,)])
+
+    def test_builder(self):
+        replay_callback = mock.Mock()
+
+        sqlur = (SQLiteUndoRedo.builder(self.test_db)
+                 .log_table('doclog')
+                 .trigger_prefix('doc')
+                 .skip_noop_updates()
+                 .track_replace()
+                 .max_total_rows(100)
+                 .coalesce(0.3)
+                 .on_replay(replay_callback)
+                 .build())
+
+        self.assertIsInstance(sqlur, SQLiteUndoRedo)
+        self.assertEqual(sqlur._log_table, 'doclog')
+        self.assertEqual(sqlur._trigger_prefix, 'doc')
+        self.assertIs(sqlur._skip_noop_updates, True)
+        self.assertIs(sqlur._track_replace, True)
+        self.assertIs(sqlur._persistent, False)
+        self.assertEqual(sqlur._max_total_rows, 100)
+        self.assertEqual(sqlur._coalesce_window, 0.3)
+        self.assertIs(sqlur._replay_callback, replay_callback)
+        self.assertIs(sqlur._strict, False)
+        self.assertEqual(sqlur._undo['active'], 0)
+
+    def test___del__(self):
+        sqlur = SQLiteUndoRedo.activated(self.test_db, ['tbl1'], trigger_prefix='app')
+
+        del sqlur
+
+        self.assertEqual(self._get_triggers(self.test_db), [])
+        q = "SELECT name FROM sqlite_temp_master WHERE type='table'"
+        self.assertEqual(self.test_db.execute(q).fetchall(), [])
+
+    def test___del___closed_connection(self):
+        sqlur = SQLiteUndoRedo.activated(self.test_db, ['tbl1'])
+        self.test_db.close()
+
+        sqlur.__del__()
+
+    def test_activated_unknown_table(self):
+        with self.assertRaises(UnknownTableError):
+            SQLiteUndoRedo.activated(self.test_db, ['does_not_exist'])
+
+    def test_recorded(self):
+        sqlur = SQLiteUndoRedo.recorded(self.test_db, ['tbl1', 'tbl2'], [
+            lambda db: db.execute("INSERT INTO tbl1 VALUES(?)", (23,)),
+            lambda db: db.execute("INSERT INTO tbl2 VALUES(?)", (42,)),
+            lambda db: db.execute("UPDATE tbl1 SET a=?", (69,)),
+        ])
+
+        self.assertEqual(sqlur._undo['undostack'], [[1, 1], [2, 2], [3, 3]])
+
+        sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(23,)])
+
+    def test___init___query_only(self):
+        self.test_db.execute("pragma query_only=1")
+
+        with self.assertRaises(ReadOnlyError):
+            SQLiteUndoRedo(self.test_db)
+
+    def test_integer_primary_key(self):
+        self.test_db.execute("CREATE TABLE tbl3(id INTEGER PRIMARY KEY, v)")
+        self.test_db.execute("INSERT INTO tbl3 VALUES(?, ?)", (5, 'x'))
+        self.sqlur.activate('tbl3')
+        self.test_db.execute("DELETE FROM tbl3")
+        self.sqlur.barrier()
+
+        self.assertEqual(self.test_db.execute("SELECT sql FROM undolog").fetchall(),
+                         [('INSERT INTO tbl3(rowid,"v") VALUES(5,\'x\')',)])
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(5, 'x')])
+
+        self.sqlur.redo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(), [])
+
+    def test_integer_primary_key_update(self):
+        self.test_db.execute("CREATE TABLE tbl3(id INTEGER PRIMARY KEY, v)")
+        self.test_db.execute("INSERT INTO tbl3 VALUES(?, ?)", (1, 'x'))
+        self.sqlur.activate('tbl3', 'tbl1')
+        self.test_db.execute("UPDATE tbl3 SET id=? WHERE id=?", (11, 1))
+        self.test_db.execute("INSERT INTO tbl1(rowid, a) VALUES(?, ?)", (5, 23))
+        self.sqlur.barrier()
+        self.test_db.execute("UPDATE tbl1 SET rowid=?, a=?", (7, 42))
+        self.sqlur.barrier()
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT rowid, a FROM tbl1").fetchall(),
+                         [(5, 23)])
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(1, 'x')])
+
+        self.sqlur.redo()
+        self.sqlur.redo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(11, 'x')])
+        self.assertEqual(self.test_db.execute("SELECT rowid, a FROM tbl1").fetchall(),
+                         [(7, 42)])
+
+    def test_insert_or_replace(self):
+        self.test_db.execute("CREATE TABLE tbl3(k UNIQUE, v)")
+        self.test_db.execute("INSERT INTO tbl3 VALUES(?, ?)", ('a', 1))
+        self.sqlur = SQLiteUndoRedo(self.test_db, track_replace=True)
+        self.sqlur.activate('tbl3')
+        self.test_db.execute("INSERT OR REPLACE INTO tbl3 VALUES(?, ?)", ('a', 2))
+        self.sqlur.barrier()
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT rowid, * FROM tbl3").fetchall(),
+                         [(1, 'a', 1)])
+
+        self.sqlur.redo()
+
+        self.assertEqual(self.test_db.execute("SELECT rowid, * FROM tbl3").fetchall(),
+                         [(2, 'a', 2)])
+
+    def test_replace_same_rowid(self):
+        self.test_db.execute("INSERT INTO tbl1(rowid, a) VALUES(?, ?)", (5, 23))
+        self.sqlur = SQLiteUndoRedo(self.test_db, track_replace=True)
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("REPLACE INTO tbl1(rowid, a) VALUES(?, ?)", (5, 42))
+        self.sqlur.barrier()
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT rowid, a FROM tbl1").fetchall(),
+                         [(5, 23)])
+
+    def test_replace_without_rowid(self):
+        self.test_db.execute("CREATE TABLE tbl3(k PRIMARY KEY, v) WITHOUT ROWID")
+        self.test_db.execute("INSERT INTO tbl3 VALUES(?, ?)", ('a', 1))
+        self.sqlur = SQLiteUndoRedo(self.test_db, track_replace=True)
+        self.sqlur.activate('tbl3')
+        self.test_db.execute("INSERT OR REPLACE INTO tbl3 VALUES(?, ?)", ('a', 2))
+        self.sqlur.barrier()
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [('a', 1)])
+
+    def test_recursive_triggers_restored(self):
+        self.sqlur = SQLiteUndoRedo(self.test_db, track_replace=True)
+        self.sqlur.activate('tbl1')
+
+        self.assertEqual(
+            self.test_db.execute("pragma recursive_triggers").fetchone()[0], 1)
+
+        self.sqlur.deactivate()
+
+        self.assertEqual(
+            self.test_db.execute("pragma recursive_triggers").fetchone()[0], 0)
+
+    def test_recursive_triggers_shared(self):
+        self.test_db.execute("CREATE TABLE tbl3(a UNIQUE, b)")
+        self.test_db.execute("INSERT INTO tbl3 VALUES(?, ?)", (1, 'x'))
+        self.sqlur = SQLiteUndoRedo(self.test_db, track_replace=True)
+        self.sqlur.activate('tbl1')
+        other = SQLiteUndoRedo.activated(self.test_db, ['tbl3'], log_table='doclog',
+                                         trigger_prefix='doc', track_replace=True)
+
+        self.sqlur.deactivate()
+
+        self.assertEqual(
+            self.test_db.execute("pragma recursive_triggers").fetchone()[0], 1)
+
+        self.test_db.execute("INSERT OR REPLACE INTO tbl3 VALUES(?, ?)", (1, 'y'))
+        other.barrier()
+        other.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(1, 'x')])
+
+        other.deactivate()
+
+        self.assertEqual(
+            self.test_db.execute("pragma recursive_triggers").fetchone()[0], 0)
+
+    def test_recursive_triggers_closed_connection(self):
+        other_db = sqlite3.connect(':memory:')
+        other_db.close()
+        db = sqlite3.connect(':memory:')
+        db.execute("CREATE TABLE tbl1(a)")
+        sqlur = SQLiteUndoRedo.activated(db, ['tbl1'], track_replace=True)
+        db.close()
+
+        with self.assertRaises(sqlite3.ProgrammingError):
+            sqlur.deactivate()
+
+        del sqlur
+
+        self.assertEqual(sys.getrefcount(db), sys.getrefcount(other_db))
+
+    def test_recursive_triggers_off(self):
+        self.test_db.execute("CREATE TABLE tbl3(a, modified)")
+        self.test_db.execute("CREATE TRIGGER tbl3_touch AFTER UPDATE ON tbl3 BEGIN"
+                             " UPDATE tbl3 SET modified=new.a WHERE rowid=new.rowid;"
+                             " END")
+        self.test_db.execute("INSERT INTO tbl3(a) VALUES(?)", (23,))
+        self.sqlur.activate('tbl3')
+
+        self.assertEqual(
+            self.test_db.execute("pragma recursive_triggers").fetchone()[0], 0)
+
+        self.test_db.execute("UPDATE tbl3 SET a=?", (42,))
+        self.sqlur.barrier()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(42, 42)])
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT a FROM tbl3").fetchall(), [(23,)])
+
+    def test_track_autoincrement(self):
+        self.test_db.execute("CREATE TABLE tbl3(id INTEGER PRIMARY KEY AUTOINCREMENT, v)")
+        self.test_db.execute("INSERT INTO tbl3(v) VALUES(?)", ('x',))
+        sqlur = SQLiteUndoRedo.activated(self.test_db, ['tbl3'],
+                                         track_autoincrement=True)
+        self.test_db.execute("INSERT INTO tbl3(v) VALUES(?)", ('y',))
+        sqlur.barrier()
+
+        sqlur.undo()
+
+        self.assertEqual(
+            self.test_db.execute("SELECT seq FROM sqlite_sequence").fetchall(), [(1,)])
+
+        sqlur.redo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(1, 'x'), (2, 'y')])
+        self.assertEqual(
+            self.test_db.execute("SELECT seq FROM sqlite_sequence").fetchall(), [(2,)])
+
+        sqlur.deactivate()
+
+        self.assertEqual(self._get_triggers(self.test_db), [])
+
+    def test_track_autoincrement_first_insert(self):
+        self.test_db.execute("CREATE TABLE tbl3(id INTEGER PRIMARY KEY AUTOINCREMENT, v)")
+        sqlur = SQLiteUndoRedo.activated(self.test_db, ['tbl3', 'tbl1'],
+                                         track_autoincrement=True)
+        self.test_db.execute("INSERT INTO tbl3(v) VALUES(?)", ('x',))
+        sqlur.barrier()
+
+        self.assertEqual(len(self._get_triggers(self.test_db)), 7)
+
+        sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM sqlite_sequence").fetchall(),
+                         [])
+
+        self.test_db.execute("INSERT INTO tbl3(v) VALUES(?)", ('y',))
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(1, 'y')])
+
+    def test_track_autoincrement_ignored_insert(self):
+        self.test_db.execute(
+            "CREATE TABLE tbl3(id INTEGER PRIMARY KEY AUTOINCREMENT, v UNIQUE)")
+        self.test_db.execute("INSERT INTO tbl3(v) VALUES(?)", ('x',))
+        sqlur = SQLiteUndoRedo.activated(self.test_db, ['tbl3'],
+                                         track_autoincrement=True)
+        self.test_db.execute("INSERT OR IGNORE INTO tbl3(v) VALUES(?)", ('x',))
+        sqlur.barrier()
+
+        self.assertEqual(sqlur._undo['undostack'], [])
+        self.assertEqual(self.test_db.execute("SELECT * FROM undolog").fetchall(), [])
+
+        self.test_db.execute("INSERT INTO tbl3(v) VALUES(?), (?)", ('y', 'z'))
+        sqlur.barrier()
+        sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(1, 'x')])
+        self.assertEqual(
+            self.test_db.execute("SELECT seq FROM sqlite_sequence").fetchall(), [(2,)])
+
+    def _get_triggers(self, db):
+        return db.execute(
+            "SELECT name FROM sqlite_temp_master WHERE type='trigger'").fetchall()
+
+    def test__create_triggers_no_tables(self):
+        self.sqlur._create_triggers(self.test_db)
+
+        self.assertEqual(self._get_triggers(self.test_db), [])
+
+    def test__create_triggers_one_table(self):
+        self.sqlur._create_triggers(self.test_db, 'tbl1')
+
+        self.assertEqual(
+            self._get_triggers(self.test_db),
+            [('_tbl1_it',), ('_tbl1_ut',), ('_tbl1_dt',)],
+        )
+
+    def test__create_triggers_several_tables(self):
+        self.sqlur._create_triggers(self.test_db, 'tbl1', 'tbl2')
+
+        self.assertEqual(len(self._get_triggers(self.test_db)), 6)
+
+    def test__create_triggers_unknown_table(self):
+        with self.assertRaisesRegex(UnknownTableError, "does_not_exist"):
+            self.sqlur._create_triggers(self.test_db, 'tbl1', 'does_not_exist')
+
+        self.assertEqual(self._get_triggers(self.test_db), [])
+
+    def test__create_triggers_table_name_case(self):
+        self.test_db.execute('CREATE TABLE "Mixed"(c)')
+
+        self.sqlur.activate('TBL1', 'mixed')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.test_db.execute("INSERT INTO Mixed VALUES(?)", (42,))
+        self.sqlur.barrier()
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [])
+        self.assertEqual(self.test_db.execute("SELECT * FROM Mixed").fetchall(), [])
+
+    def test__create_triggers_generated_columns(self):
+        self.test_db.execute(
+            "CREATE TABLE tbl3(a, b AS (a*2) STORED, c AS (a+1) VIRTUAL)")
+        self.sqlur.activate('tbl3')
+        self.test_db.execute("INSERT INTO tbl3(a) VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("UPDATE tbl3 SET a=?", (42,))
+        self.sqlur.barrier()
+        self.test_db.execute("DELETE FROM tbl3")
+        self.sqlur.barrier()
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(42, 84, 43)])
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(23, 46, 24)])
+
+        self.sqlur.undo()
+        self.sqlur.redo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(23, 46, 24)])
+
+    def test__create_triggers_no_columns(self):
+        self.test_db.execute("CREATE TABLE tbl3(a, b AS (a*2))")
+        self.sqlur.exclude_column('tbl3', 'a')
+        self.sqlur.activate('tbl3')
+
+        self.assertEqual(self._get_triggers(self.test_db), [('_tbl3_it',), ('_tbl3_dt',)])
+
+        self.test_db.execute("INSERT INTO tbl3 VALUES(?)", (23,))
+        self.test_db.execute("UPDATE tbl3 SET a=?", (42,))
+        self.sqlur.barrier()
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(), [])
+
+    def test__create_triggers_skip_noop_updates(self):
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        sqlur = SQLiteUndoRedo.activated(self.test_db, ['tbl1'],
+                                         skip_noop_updates=True)
+
+        self.test_db.execute("UPDATE tbl1 SET a=a")
+        sqlur.barrier()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM undolog").fetchall(), [])
+        self.assertEqual(sqlur._undo['undostack'], [])
+
+        self.test_db.execute("UPDATE tbl1 SET a=?", (42,))
+        sqlur.barrier()
+        sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(23,)])
+
+    def test__create_triggers_error(self):
+        self.test_db.execute("CREATE TEMP TRIGGER _tbl2_ut AFTER UPDATE ON tbl2 BEGIN"
+                             " SELECT 1; END")
+
+        with self.assertRaises(sqlite3.OperationalError):
+            self.sqlur._create_triggers(self.test_db, 'tbl1', 'tbl2')
 
-    def test__create_triggers_no_tables(self):
-        self.sqlur._create_triggers(self.test_db)
+        self.assertEqual(self._get_triggers(self.test_db), [('_tbl2_ut',)])
 
-        self.assertEqual(self._get_triggers(self.test_db), [])
+    def test__create_triggers_permanent_undolog(self):
+        self.test_db.execute("CREATE TABLE undolog(x)")
+        self.test_db.execute("INSERT INTO undolog VALUES(?)", (23,))
 
-    def test__create_triggers_one_table(self):
+        with self.assertRaises(UndoError):
+            self.sqlur._create_triggers(self.test_db, 'tbl1')
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM main.undolog").fetchall(),
+                         [(23,)])
+
+    def test__create_triggers_recreates_temp_undolog(self):
         self.sqlur._create_triggers(self.test_db, 'tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
 
-        self.assertEqual(
-            self._get_triggers(self.test_db),
-            [('_tbl1_it',), ('_tbl1_ut',), ('_tbl1_dt',)],
-        )
+        self.sqlur._create_triggers(self.test_db)
 
-    def test__create_triggers_several_tables(self):
-        self.sqlur._create_triggers(self.test_db, 'tbl1', 'tbl2')
+        self.assertEqual(self.test_db.execute("SELECT * FROM undolog").fetchall(), [])
+
+    def test__create_triggers_keep_log(self):
+        self.sqlur._create_triggers(self.test_db, 'tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
 
+        self.sqlur._create_triggers(self.test_db, 'tbl1', 'tbl2', keep_log=True)
+        self.test_db.execute("INSERT INTO tbl2 VALUES(?)", (42,))
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM undolog").fetchall(),
+                         [(1, 'DELETE FROM tbl1 WHERE rowid=1'),
+                          (2, 'DELETE FROM tbl2 WHERE rowid=1')])
         self.assertEqual(len(self._get_triggers(self.test_db)), 6)
 
+    def test__create_triggers_keep_log_unknown_table(self):
+        self.sqlur._create_triggers(self.test_db, 'tbl1')
+
+        with self.assertRaises(UnknownTableError):
+            self.sqlur._create_triggers(self.test_db, 'tbl1', 'does_not_exist',
+                                        keep_log=True)
+
+        self.assertEqual(len(self._get_triggers(self.test_db)), 3)
+
     def test__drop_triggers(self):
-        self.sqlur._create_triggers(self.test_db, 'tbl1', 'tbl2')
+        self.sqlur.activate('tbl1', 'tbl2')
 
         self.sqlur._drop_triggers(self.test_db)
 
         self.assertEqual(self._get_triggers(self.test_db), [])
 
+    def test__drop_triggers_old_sqlite(self):
+        test_db = self.test_db
+
+        class OldDb:
+            # Before SQLite 3.33.0, sqlite_temp_schema does not exist
+            def execute(self, sql, *args):
+                if 'sqlite_temp_schema' in sql:
+                    raise sqlite3.OperationalError("no such table: sqlite_temp_schema")
+                return test_db.execute(sql, *args)
+
+        self.sqlur.activate('tbl1', 'tbl2')
+
+        self.sqlur._drop_triggers(OldDb())
+
+        self.assertEqual(self._get_triggers(self.test_db), [])
+
+    def test__drop_triggers_prefix(self):
+        sqlur = SQLiteUndoRedo(self.test_db, trigger_prefix='myapp_')
+        self.test_db.execute("CREATE TEMP TRIGGER _tbl2_it AFTER INSERT ON tbl2 BEGIN"
+                             " SELECT 1; END")
+        sqlur.activate('tbl1')
+
+        self.assertEqual(
+            self._get_triggers(self.test_db),
+            [('_tbl2_it',),
+             ('myapp__tbl1_it',), ('myapp__tbl1_ut',), ('myapp__tbl1_dt',)],
+        )
+
+        sqlur._drop_triggers(self.test_db)
+
+        self.assertEqual(self._get_triggers(self.test_db), [('_tbl2_it',)])
+
     def test__start_interval(self):
         self.sqlur.activate('tbl1')
 
@@ -361,13 +2930,229 @@ class SQLiteUndoRedoTest(unittest.TestCase):
 
         self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(69,)])
 
-        self.sqlur.undo()
+        self.assertEqual(self.sqlur.undo(), 4)
 
         self.assertEqual(self.sqlur._undo['undostack'], [])
         self.assertEqual(self.sqlur._undo['redostack'], [[1, 4]])
         self.assertEqual(self.sqlur._undo['firstlog'], 5)
         self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [])
 
+    def test__step_undo_blob_and_null(self):
+        blob = bytes(range(256)) + b"'\x00'"
+        self.test_db.execute("CREATE TABLE tbl3(a, b)")
+        self.test_db.execute("INSERT INTO tbl3 VALUES(?, ?)", (blob, None))
+        self.sqlur.activate('tbl3')
+        self.test_db.execute("UPDATE tbl3 SET a=?, b=?", (None, b"\x00"))
+        self.sqlur.barrier()
+        self.test_db.execute("DELETE FROM tbl3")
+        self.sqlur.barrier()
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(None, b"\x00")])
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(blob, None)])
+
+    def test__step_undo_quotes_newlines_and_concatenation(self):
+        texts = ["it's", "''", "a'||'b", "line 1\nline 2\r\n", "' || quote(a) || '",
+                 "'); DROP TABLE tbl1; --"]
+        self.test_db.execute("CREATE TABLE tbl3(a, b)")
+        self.test_db.executemany("INSERT INTO tbl3 VALUES(?, ?)",
+                                 [(text, text + "'") for text in texts])
+        rows = self.test_db.execute("SELECT rowid, * FROM tbl3").fetchall()
+        self.sqlur.activate('tbl3')
+        self.test_db.execute("UPDATE tbl3 SET a=b, b='x'")
+        self.sqlur.barrier()
+        self.test_db.execute("DELETE FROM tbl3")
+        self.sqlur.barrier()
+
+        self.sqlur.undo()
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT rowid, * FROM tbl3").fetchall(),
+                         rows)
+
+        self.sqlur.redo()
+        self.sqlur.redo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(), [])
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [])
+
+    def test__step_undo_keyword_columns(self):
+        self.test_db.execute('CREATE TABLE tbl3("order", "group", "it\'s ""x""")')
+        self.test_db.execute("INSERT INTO tbl3 VALUES(?, ?, ?)", (1, 'a', 'b'))
+        self.sqlur = SQLiteUndoRedo(self.test_db, skip_noop_updates=True)
+        self.sqlur.activate('tbl3')
+        self.test_db.execute('UPDATE tbl3 SET "order"=?, "it\'s ""x"""=?', (2, 'c'))
+        self.sqlur.barrier()
+
+        self.assertEqual(
+            self.sqlur.structured_undo(),
+            [ChangeRecord('tbl3', 'UPDATE', 1,
+                          {'order': 1, 'group': 'a', 'it\'s "x"': 'b'})])
+
+        self.test_db.execute("DELETE FROM tbl3")
+        self.sqlur.barrier()
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(2, 'a', 'c')])
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(1, 'a', 'b')])
+
+    def test__step_undo_keyword_primary_key(self):
+        self.test_db.execute('CREATE TABLE tbl3("order" PRIMARY KEY, "group")'
+                             " WITHOUT ROWID")
+        self.sqlur.activate('tbl3')
+        self.test_db.execute("INSERT INTO tbl3 VALUES(?, ?)", (1, 'a'))
+        self.sqlur.barrier()
+        self.test_db.execute('UPDATE tbl3 SET "group"=?', ('b',))
+        self.sqlur.barrier()
+
+        self.assertEqual(
+            self.sqlur.structured_undo(),
+            [ChangeRecord('tbl3', 'UPDATE', None, {'order': 1, 'group': 'a'})])
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(1, 'a')])
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(), [])
+
+    def test__step_undo_without_rowid(self):
+        self.test_db.execute("CREATE TABLE tbl3(a, b, c, PRIMARY KEY(a, b))"
+                             " WITHOUT ROWID")
+        self.test_db.executemany("INSERT INTO tbl3 VALUES(?, ?, ?)",
+                                 [(1, 1, 'x'), (1, 2, 'y')])
+        self.sqlur.activate('tbl3')
+        self.test_db.execute("UPDATE tbl3 SET b=?, c=? WHERE a=? AND b=?",
+                             (3, 'z', 1, 2))
+        self.sqlur.barrier()
+        self.test_db.execute("DELETE FROM tbl3 WHERE a=? AND b=?", (1, 1))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl3 VALUES(?, ?, ?)", (2, 1, 'w'))
+        self.sqlur.barrier()
+
+        self.sqlur.undo()
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(1, 1, 'x'), (1, 3, 'z')])
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(1, 1, 'x'), (1, 2, 'y')])
+
+        self.sqlur.redo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(1, 1, 'x'), (1, 3, 'z')])
+
+    def test__step_reentrant_barrier(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+        self.sqlur.barrier()
+
+        sqlur = self.sqlur
+        test_db = self.test_db
+
+        class ReentrantDb:
+            def execute(self, sql, *args):
+                cursor = test_db.execute(sql, *args)
+                if sql.startswith("DELETE FROM tbl1"):
+                    sqlur.barrier()
+                return cursor
+
+        with mock.patch.object(self.sqlur, '_db', ReentrantDb()):
+            self.sqlur.undo()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+        self.assertEqual(self.sqlur._undo['redostack'], [[2, 2]])
+        self.assertEqual(self.sqlur._undo['firstlog'], 3)
+
+        self.sqlur.redo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(23,), (42,)])
+
+    def test__step_undo_view(self):
+        self.test_db.executescript("""
+            CREATE TABLE tbl3(a, b);
+            CREATE VIEW view3 AS SELECT a, b FROM tbl3;
+            CREATE TRIGGER view3_it INSTEAD OF INSERT ON view3 BEGIN
+              INSERT INTO tbl3 VALUES(new.a, new.b);
+            END;
+            CREATE TRIGGER view3_ut INSTEAD OF UPDATE ON view3 BEGIN
+              UPDATE tbl3 SET a=new.a, b=new.b WHERE a IS old.a AND b IS old.b;
+            END;
+            CREATE TRIGGER view3_dt INSTEAD OF DELETE ON view3 BEGIN
+              DELETE FROM tbl3 WHERE a IS old.a AND b IS old.b;
+            END;
+        """)
+        self.sqlur.activate('view3')
+        self.test_db.execute("INSERT INTO view3 VALUES(?, ?)", (23, None))
+        self.sqlur.barrier()
+        self.test_db.execute("UPDATE view3 SET b=?", ('x',))
+        self.sqlur.barrier()
+        self.test_db.execute("DELETE FROM view3")
+        self.sqlur.barrier()
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(23, 'x')])
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(),
+                         [(23, None)])
+
+        self.sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl3").fetchall(), [])
+
+    def test__step_in_transaction(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute('BEGIN')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+
+        self.sqlur.undo()
+
+        self.assertTrue(self.test_db.in_transaction)
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [])
+
+        self.sqlur.redo()
+        self.test_db.execute('COMMIT')
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(23,)])
+
+    def test__step_missing_log_rows(self):
+        self.sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        self.sqlur.barrier()
+        self.test_db.execute("DELETE FROM undolog")
+
+        with self.assertRaises(CorruptLogError):
+            self.sqlur.undo()
+
+        self.assertEqual(self.sqlur._undo['undostack'], [[1, 1]])
+        self.assertEqual(self.sqlur._undo['redostack'], [])
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(23,)])
+
     def test__step_redo_insert(self):
         self.sqlur.activate('tbl1')
         self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
@@ -428,7 +3213,7 @@ class SQLiteUndoRedoTest(unittest.TestCase):
 
         self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [])
 
-        self.sqlur.redo()
+        self.assertEqual(self.sqlur.redo(), 4)
 
         self.assertEqual(self.sqlur._undo['undostack'], [[1, 4]])
         self.assertEqual(self.sqlur._undo['redostack'], [])
@@ -436,5 +3221,294 @@ class SQLiteUndoRedoTest(unittest.TestCase):
         self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [(69,)])
 
 
+class PersistentSQLiteUndoRedoTest(unittest.TestCase):
+
+    def setUp(self):
+        self.test_dir = tempfile.TemporaryDirectory()
+        self.test_path = os.path.join(self.test_dir.name, 'test.db')
+        self.test_db = self._connect()
+        self.test_db.execute("CREATE TABLE tbl1(a)")
+
+    def tearDown(self):
+        self.test_db.close()
+        self.test_dir.cleanup()
+
+    def _connect(self):
+        db = sqlite3.connect(self.test_path)
+        db.isolation_level = None
+        return db
+
+    def _reconnect(self):
+        self.test_db.close()
+        self.test_db = self._connect()
+
+    def test_activate(self):
+        sqlur = SQLiteUndoRedo(self.test_db, persistent=True)
+        sqlur.activate('tbl1')
+
+        self.assertEqual(
+            self.test_db.execute(
+                "SELECT name FROM sqlite_master WHERE name LIKE 'undo%'").fetchall(),
+            [('undolog',), ('undostate',)],
+        )
+
+    def test_history_survives_reconnection(self):
+        sqlur = SQLiteUndoRedo(self.test_db, persistent=True)
+        sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+        sqlur.barrier()
+        sqlur.undo()
+
+        self._reconnect()
+        sqlur = SQLiteUndoRedo(self.test_db, persistent=True)
+        sqlur.activate('tbl1')
+
+        self.assertEqual(sqlur._undo['undostack'], [[1, 1]])
+        self.assertEqual(sqlur._undo['redostack'], [[2, 2]])
+        self.assertEqual(sqlur._undo['firstlog'], 3)
+
+        sqlur.redo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(23,), (42,)])
+
+        sqlur.undo()
+        sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [])
+
+    def test_pending_changes_survive_reconnection(self):
+        sqlur = SQLiteUndoRedo(self.test_db, persistent=True)
+        sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        sqlur.barrier()
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+
+        self._reconnect()
+        sqlur = SQLiteUndoRedo(self.test_db, persistent=True)
+        sqlur.activate('tbl1')
+        sqlur.barrier()
+
+        self.assertEqual(sqlur._undo['undostack'], [[1, 1], [2, 2]])
+        self.assertTrue(sqlur.verify())
+
+        sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(),
+                         [(23,)])
+
+    def test___del__(self):
+        sqlur = SQLiteUndoRedo(self.test_db, persistent=True)
+        sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        sqlur.barrier()
+
+        del sqlur
+
+        self.assertEqual(
+            self.test_db.execute(
+                "SELECT name FROM sqlite_temp_master WHERE type='trigger'").fetchall(),
+            [])
+        sqlur = SQLiteUndoRedo(self.test_db, persistent=True)
+        sqlur.activate('tbl1')
+        self.assertEqual(sqlur._undo['undostack'], [[1, 1]])
+
+    def test___del___recursive_triggers(self):
+        sqlur = SQLiteUndoRedo(self.test_db, persistent=True, track_replace=True)
+        sqlur.activate('tbl1')
+
+        self.assertEqual(
+            self.test_db.execute("pragma recursive_triggers").fetchone()[0], 1)
+
+        del sqlur
+
+        self.assertEqual(
+            self.test_db.execute("pragma recursive_triggers").fetchone()[0], 0)
+
+    def test_rebind(self):
+        sqlur = SQLiteUndoRedo(self.test_db, persistent=True)
+        sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        other_db = self._connect()
+
+        sqlur.rebind(other_db)
+
+        self.assertIs(sqlur._db, other_db)
+        self.assertEqual(sqlur._undo['undostack'], [[1, 1]])
+        self.assertEqual(
+            self.test_db.execute(
+                "SELECT name FROM sqlite_temp_master WHERE type='trigger'").fetchall(),
+            [])
+
+        other_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+        sqlur.barrier()
+        sqlur.undo()
+        sqlur.undo()
+
+        self.assertEqual(other_db.execute("SELECT * FROM tbl1").fetchall(), [])
+        other_db.close()
+
+    def test_rebind_not_persistent(self):
+        sqlur = SQLiteUndoRedo(self.test_db)
+        sqlur.activate('tbl1')
+        other_db = self._connect()
+
+        with self.assertRaises(UndoError):
+            sqlur.rebind(other_db)
+
+        self.assertIs(sqlur._db, self.test_db)
+        other_db.close()
+
+    def test_log_schema_wal(self):
+        log_path = os.path.join(self.test_dir.name, 'log.db')
+        self.test_db.execute("ATTACH DATABASE ? AS undo", (log_path,))
+        self.test_db.execute("PRAGMA undo.journal_mode=WAL")
+        sqlur = SQLiteUndoRedo(self.test_db, persistent=True, log_schema='undo')
+        sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        sqlur.barrier()
+
+        reader_db = sqlite3.connect(log_path)
+        read_log = "SELECT seq, sql FROM undolog ORDER BY seq"
+
+        self.assertEqual(reader_db.execute(read_log).fetchall(),
+                         [(1, 'DELETE FROM tbl1 WHERE rowid=1')])
+
+        self.test_db.execute('BEGIN')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (42,))
+
+        self.assertEqual(reader_db.execute(read_log).fetchall(),
+                         [(1, 'DELETE FROM tbl1 WHERE rowid=1')])
+
+        self.test_db.execute('COMMIT')
+        sqlur.barrier()
+
+        self.assertEqual(reader_db.execute(read_log).fetchall(),
+                         [(1, 'DELETE FROM tbl1 WHERE rowid=1'),
+                          (2, 'DELETE FROM tbl1 WHERE rowid=2')])
+        self.assertEqual(
+            reader_db.execute("SELECT stack, first, last FROM undostate").fetchall(),
+            [('undostack', 1, 1), ('undostack', 2, 2)])
+        reader_db.close()
+
+    def test_memory_log(self):
+        sqlur = SQLiteUndoRedo(self.test_db, memory_log=True)
+        sqlur.activate('tbl1')
+        self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+        sqlur.barrier()
+
+        self.assertEqual(
+            self.test_db.execute("SELECT seq, sql FROM undo_memory.undolog").fetchall(),
+            [(1, 'DELETE FROM tbl1 WHERE rowid=1')])
+        self.assertEqual(
+            self.test_db.execute(
+                "SELECT name FROM sqlite_temp_master WHERE type='table'").fetchall(),
+            [])
+
+        sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [])
+
+        sqlur.deactivate()
+
+        self.assertNotIn(
+            'undo_memory',
+            [name for (x1, name, x2) in self.test_db.execute("pragma database_list")])
+
+    def test_deactivate(self):
+        sqlur = SQLiteUndoRedo(self.test_db, persistent=True)
+        sqlur.activate('tbl1')
+
+        sqlur.deactivate()
+
+        self.assertEqual(
+            self.test_db.execute(
+                "SELECT name FROM sqlite_master WHERE name LIKE 'undo%'").fetchall(),
+            [],
+        )
+
+
+class SharedSQLiteUndoRedoTest(unittest.TestCase):
+
+    def setUp(self):
+        self.test_db = sqlite3.connect(':memory:', check_same_thread=False)
+        self.test_db.isolation_level = None
+        self.test_db.execute("CREATE TABLE tbl1(a)")
+
+        self.shared = SharedSQLiteUndoRedo(self.test_db)
+
+    def tearDown(self):
+        self.test_db.close()
+
+    def test_lock(self):
+        with self.shared.lock() as sqlur:
+            self.assertIsInstance(sqlur, SQLiteUndoRedo)
+            self.assertTrue(self.shared._lock.locked())
+
+        self.assertFalse(self.shared._lock.locked())
+
+    def test_threads(self):
+        with self.shared.lock() as sqlur:
+            sqlur.activate('tbl1')
+
+        def edit(value):
+            with self.shared.lock() as sqlur:
+                self.test_db.execute("INSERT INTO tbl1 VALUES(?)", (value,))
+                sqlur.barrier()
+
+        threads = [threading.Thread(target=edit, args=(value,)) for value in (23, 42)]
+        for thread in threads:
+            thread.start()
+        for thread in threads:
+            thread.join()
+
+        with self.shared.lock() as sqlur:
+            self.assertEqual(sqlur._undo['undostack'], [[1, 1], [2, 2]])
+            sqlur.undo()
+            sqlur.undo()
+
+        self.assertEqual(self.test_db.execute("SELECT * FROM tbl1").fetchall(), [])
+
+
+class AsyncSQLiteUndoRedoTest(unittest.TestCase):
+
+    def setUp(self):
+        self.test_db = sqlite3.connect(':memory:', check_same_thread=False)
+        self.test_db.isolation_level = None
+        self.test_db.execute("CREATE TABLE tbl1(a)")
+
+        self.async_sqlur = AsyncSQLiteUndoRedo(self.test_db)
+        self.loop = asyncio.new_event_loop()
+
+    def tearDown(self):
+        self.loop.close()
+        self.async_sqlur.close()
+        self.test_db.close()
+
+    def test_undo(self):
+        async def edit():
+            await self.async_sqlur.activate('tbl1')
+            await self.async_sqlur.execute("INSERT INTO tbl1 VALUES(?)", (23,))
+            await self.async_sqlur.barrier()
+            self.assertEqual(await self.async_sqlur.undo(), 1)
+            return await self.async_sqlur.execute("SELECT * FROM tbl1")
+
+        self.assertEqual(self.loop.run_until_complete(edit()), [])
+        self.assertEqual(self.async_sqlur._sqlur._undo['redostack'], [[1, 1]])
+
+    def test_run(self):
+        main_thread = threading.get_ident()
+
+        def get_thread(sqlur):
+            self.assertIs(sqlur, self.async_sqlur._sqlur)
+            return threading.get_ident()
+
+        worker_thread = self.loop.run_until_complete(self.async_sqlur.run(get_thread))
+
+        self.assertNotEqual(worker_thread, main_thread)
+
+
 if __name__ == '__main__':
     unittest.main()